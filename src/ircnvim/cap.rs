@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/*
+ * The capabilities we ask for if the profile doesn't specify any.
+ */
+pub const DEFAULT_CAPS: &'static [&'static str] = &[
+    "cap-notify",
+    "message-tags",
+    "multi-prefix",
+    "server-time"
+];
+
+/*
+ * The longest list of capabilities we put in a single CAP REQ, so that
+ * the line stays well within the 512 byte limit.
+ */
+const MAX_REQ_LENGTH: usize = 400;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CapState {
+    Idle,       // we never sent CAP LS, or the server doesn't support CAP
+    Listing,    // waiting for the (possibly multi-line) reply to CAP LS
+    Requesting, // waiting for ACK / NAK replies to our CAP REQs
+    Done        // negotiation has finished
+}
+
+pub struct Caps {
+    pub state: CapState,
+    available: HashMap<String, Option<String>>,
+    enabled: HashSet<String>,
+    wanted: Vec<String>,
+    pending: usize
+}
+
+impl Caps {
+    pub fn new(wanted: Vec<String>) -> Caps {
+        return Caps {
+            state: CapState::Idle,
+            available: HashMap::new(),
+            enabled: HashSet::new(),
+            wanted: wanted,
+            pending: 0
+        };
+    }
+
+    /*
+     * Record the capabilities in `list` (the last parameter of CAP LS or
+     * CAP NEW) as available, along with their values, if any.
+     *
+     * e.g., "multi-prefix sasl=PLAIN,EXTERNAL server-time"
+     */
+    pub fn add_available(&mut self, list: &str) {
+        for cap in list.split_whitespace() {
+            match cap.find('=') {
+                Some(i) => self.available.insert(cap[..i].to_string(), Some(cap[i+1..].to_string())),
+                None    => self.available.insert(cap.to_string(), None)
+            };
+        }
+    }
+
+    /*
+     * Handle CAP DEL: the capabilities in `list` are no longer available.
+     */
+    pub fn remove(&mut self, list: &str) {
+        for cap in list.split_whitespace() {
+            self.available.remove(cap);
+            self.enabled.remove(cap);
+        }
+    }
+
+    /*
+     * Build the CAP REQ lines for every capability that we want, that the server
+     * offers, and that isn't already enabled. If `only` is given, only the
+     * capabilities it contains are considered (used for CAP NEW).
+     *
+     * Each returned line is counted as pending until it is ACKed or NAKed.
+     */
+    pub fn requests(&mut self, only: Option<&str>) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut line = String::new();
+
+        for cap in &self.wanted {
+            if !self.available.contains_key(cap) || self.enabled.contains(cap) { continue }
+            if let Some(list) = only {
+                if !list.split_whitespace().any(|c| c.split('=').next() == Some(&cap[..])) { continue }
+            }
+            if !line.is_empty() && line.len() + cap.len() + 1 > MAX_REQ_LENGTH {
+                lines.push(format!("CAP REQ :{}", line));
+                line = String::new();
+            }
+            if !line.is_empty() { line.push(' ') }
+            line.push_str(cap);
+        }
+
+        if !line.is_empty() {
+            lines.push(format!("CAP REQ :{}", line));
+        }
+
+        self.pending += lines.len();
+
+        /*
+         * If there is nothing to ask for, negotiation is already over.
+         */
+        if self.pending == 0 && self.state == CapState::Requesting {
+            self.state = CapState::Done;
+        }
+
+        return lines;
+    }
+
+    /*
+     * Handle CAP ACK. Capabilities prefixed with '-' have been disabled.
+     */
+    pub fn ack(&mut self, list: &str) {
+        for cap in list.split_whitespace() {
+            if cap.starts_with('-') {
+                self.enabled.remove(&cap[1..]);
+            } else {
+                self.enabled.insert(cap.to_string());
+            }
+        }
+        self.answered();
+    }
+
+    /*
+     * Handle CAP NAK. The whole request was rejected, so nothing changes.
+     */
+    pub fn nak(&mut self, _list: &str) {
+        self.answered();
+    }
+
+    fn answered(&mut self) {
+        if self.pending > 0 {
+            self.pending -= 1;
+        }
+        if self.pending == 0 && self.state == CapState::Requesting {
+            self.state = CapState::Done;
+        }
+    }
+
+    pub fn is_available(&self, cap: &str) -> bool {
+        return self.available.contains_key(cap);
+    }
+
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        return self.enabled.contains(cap);
+    }
+
+    /*
+     * Returns the value the server advertised for `cap`, e.g. "PLAIN,EXTERNAL" for sasl.
+     */
+    pub fn value(&self, cap: &str) -> Option<&str> {
+        return self.available.get(cap).and_then(|v| v.as_ref()).map(|s| &s[..]);
+    }
+
+    /*
+     * The enabled capabilities, sorted so that they can be displayed.
+     */
+    pub fn enabled(&self) -> Vec<&str> {
+        let mut caps = self.enabled.iter().map(|s| &s[..]).collect::<Vec<_>>();
+        caps.sort();
+        return caps;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn wanting(wanted: &[&str]) -> Caps {
+        return Caps::new(wanted.iter().map(|s| s.to_string()).collect());
+    }
+
+    #[test]
+    fn test_multi_line_ls() {
+        let mut caps = wanting(&["sasl", "multi-prefix", "server-time"]);
+        caps.add_available("multi-prefix sasl=PLAIN,EXTERNAL");
+        caps.add_available("away-notify server-time");
+        assert!(caps.is_available("away-notify"));
+        assert_eq!(caps.value("sasl"), Some("PLAIN,EXTERNAL"));
+        assert_eq!(caps.value("multi-prefix"), None);
+        assert_eq!(caps.requests(None), vec!["CAP REQ :sasl multi-prefix server-time".to_string()]);
+    }
+
+    #[test]
+    fn test_only_wanted_and_available() {
+        let mut caps = wanting(&["sasl", "extended-join"]);
+        caps.add_available("extended-join account-notify");
+        assert_eq!(caps.requests(None), vec!["CAP REQ :extended-join".to_string()]);
+    }
+
+    #[test]
+    fn test_nothing_to_request() {
+        let mut caps = wanting(&["sasl"]);
+        caps.state = CapState::Requesting;
+        caps.add_available("multi-prefix");
+        assert!(caps.requests(None).is_empty());
+        assert_eq!(caps.state, CapState::Done);
+    }
+
+    #[test]
+    fn test_ack_nak() {
+        let mut caps = wanting(&["sasl", "multi-prefix"]);
+        caps.state = CapState::Requesting;
+        caps.add_available("sasl multi-prefix");
+        caps.requests(None);
+        caps.ack("sasl multi-prefix");
+        assert!(caps.is_enabled("sasl"));
+        assert_eq!(caps.state, CapState::Done);
+
+        caps.ack("-sasl");
+        assert!(!caps.is_enabled("sasl"));
+        assert!(caps.is_enabled("multi-prefix"));
+
+        let mut caps = wanting(&["sasl"]);
+        caps.state = CapState::Requesting;
+        caps.add_available("sasl");
+        caps.requests(None);
+        caps.nak("sasl");
+        assert!(!caps.is_enabled("sasl"));
+        assert_eq!(caps.state, CapState::Done);
+    }
+
+    #[test]
+    fn test_new_del() {
+        let mut caps = wanting(&["away-notify", "sasl"]);
+        caps.add_available("sasl");
+        caps.requests(None);
+        caps.ack("sasl");
+
+        caps.add_available("away-notify");
+        assert_eq!(caps.requests(Some("away-notify")), vec!["CAP REQ :away-notify".to_string()]);
+        caps.ack("away-notify");
+
+        caps.remove("sasl");
+        assert!(!caps.is_available("sasl"));
+        assert!(!caps.is_enabled("sasl"));
+        assert_eq!(caps.enabled(), vec!["away-notify"]);
+    }
+
+    #[test]
+    fn test_long_request_is_split() {
+        let wanted = (0..100).map(|i| format!("vendor.example/capability-{}", i)).collect::<Vec<_>>();
+        let mut caps = Caps::new(wanted.clone());
+        caps.add_available(&wanted.join(" "));
+        let lines = caps.requests(None);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|l| l.len() < 512));
+    }
+}
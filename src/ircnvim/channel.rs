@@ -1,60 +1,188 @@
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::mem;
+use ircnvim::isupport::CaseMapping;
+use ircnvim::isupport::ServerInfo;
+use ircnvim::mode::ModeChange;
+use ircnvim::mode::ModeKind;
 use ircnvim::user::User;
 
-const CHANNEL_STARTING_CHARACTERS: &'static str = "#&+!~";
-
+/*
+ * Whether a name is a channel depends on the CHANTYPES the server advertises.
+ */
 pub trait IsChannelName {
-    fn is_channel_name(&self) -> bool;
+    fn is_channel_name(&self, info: &ServerInfo) -> bool;
 }
 
 impl<'a> IsChannelName for &'a str {
-    fn is_channel_name(&self) -> bool {
-        return self.chars().nth(0).and_then(|c| CHANNEL_STARTING_CHARACTERS.find(c)).is_some();
+    fn is_channel_name(&self, info: &ServerInfo) -> bool {
+        return info.is_channel_name(self);
     }
 }
 
 impl IsChannelName for String {
-    fn is_channel_name(&self) -> bool {
-        return (&self[..]).is_channel_name();
+    fn is_channel_name(&self, info: &ServerInfo) -> bool {
+        return (&self[..]).is_channel_name(info);
     }
 }
 
+/*
+ * A user's membership of a channel, along with the modes that give them a rank
+ * in it (e.g. 'o' and 'v' for someone who is both an op and voiced).
+ */
+pub struct Member {
+    pub user: User,
+    pub modes: Vec<char>
+}
+
+/*
+ * Members are keyed by their nick folded according to the server's casemapping,
+ * but keep the case they were first seen with for display.
+ */
 pub struct Channel {
     pub name: String,
     pub topic: Option<String>,
-    users: HashSet<User>
+    pub topic_setter: Option<String>,
+    pub topic_time: Option<i64>,
+    pub joined: bool,
+    casemapping: CaseMapping,
+    users: HashMap<String, Member>,
+    modes: BTreeMap<char, Option<String>>
 }
 
 impl Channel {
-    pub fn new(name: &str) -> Channel {
+    pub fn new(name: &str, casemapping: CaseMapping) -> Channel {
         return Channel {
             name: name.to_string(),
             topic: None,
-            users: HashSet::new()
+            topic_setter: None,
+            topic_time: None,
+            joined: true,
+            casemapping: casemapping,
+            users: HashMap::new(),
+            modes: BTreeMap::new()
         };
     }
 
     pub fn add_user(&mut self, user: User) {
-        self.users.insert(user);
+        self.add_member(user, Vec::new());
+    }
+
+    /*
+     * Add a user along with their membership modes, e.g. from a NAMES reply. If they
+     * are already here, their modes are replaced.
+     */
+    pub fn add_member(&mut self, user: User, modes: Vec<char>) {
+        let key = self.casemapping.fold(&user.nick);
+        self.users.entry(key).or_insert(Member { user: user, modes: Vec::new() }).modes = modes;
     }
 
     pub fn remove_user(&mut self, user: &User) {
-        self.users.remove(user);
+        self.users.remove(&self.casemapping.fold(&user.nick));
     }
 
     pub fn rename(&mut self, user: &User, new_nick: &str) {
-        self.users.remove(user);
-        self.users.insert(User::from_nick(new_nick.to_string()));
+        let key = self.casemapping.fold(&user.nick);
+        if let Some(mut member) = self.users.remove(&key) {
+            member.user = User::from_nick(new_nick.to_string());
+            self.users.insert(self.casemapping.fold(new_nick), member);
+        }
+    }
+
+    /*
+     * Apply a change to a member's modes, e.g. +o or -v.
+     */
+    pub fn set_member_mode(&mut self, nick: &str, mode: char, adding: bool) {
+        if let Some(member) = self.users.get_mut(&self.casemapping.fold(nick)) {
+            member.modes.retain(|&m| m != mode);
+            if adding {
+                member.modes.push(mode);
+            }
+        }
+    }
+
+    /*
+     * Apply a change from MODE or RPL_CHANNELMODEIS. Lists (bans, etc.) aren't tracked.
+     */
+    pub fn apply_mode(&mut self, change: &ModeChange) {
+        match change.kind {
+            ModeKind::Membership => {
+                if let Some(ref nick) = change.param {
+                    self.set_member_mode(nick, change.mode, change.adding);
+                }
+            },
+            ModeKind::List       => { },
+            _                    => {
+                if change.adding {
+                    self.modes.insert(change.mode, change.param.clone());
+                } else {
+                    self.modes.remove(&change.mode);
+                }
+            }
+        }
+    }
+
+    /*
+     * Forget the channel's modes, before RPL_CHANNELMODEIS tells us all of them again.
+     */
+    pub fn clear_modes(&mut self) {
+        self.modes.clear();
+    }
+
+    pub fn mode(&self, mode: char) -> Option<Option<&str>> {
+        return self.modes.get(&mode).map(|p| p.as_ref().map(|s| &s[..]));
+    }
+
+    /*
+     * The channel's modes for display, e.g. "+klnt". Parameters (like the key) are left out.
+     */
+    pub fn mode_string(&self) -> String {
+        if self.modes.is_empty() {
+            return String::new();
+        }
+        return Some('+').into_iter().chain(self.modes.keys().cloned()).collect();
+    }
+
+    pub fn member(&self, nick: &str) -> Option<&Member> {
+        return self.users.get(&self.casemapping.fold(nick));
+    }
+
+    /*
+     * Switch to the casemapping the server uses, which we only learn once we've
+     * registered, and key the members by it.
+     */
+    pub fn set_casemapping(&mut self, casemapping: CaseMapping) {
+        let users = mem::replace(&mut self.users, HashMap::new());
+        self.users = users.into_iter().map(|(_, member)| (casemapping.fold(&member.user.nick), member)).collect();
+        self.casemapping = casemapping;
+    }
+
+    pub fn clear_users(&mut self) {
+        self.users.clear();
     }
 
     pub fn is_user_present(&self, user: &User) -> bool {
-        return self.users.contains(user);
+        return self.users.contains_key(&self.casemapping.fold(&user.nick));
     }
 
     pub fn set_topic(&mut self, topic: String) {
         self.topic = Some(topic);
     }
 
+    /*
+     * Record who set the topic, and when (seconds since the epoch).
+     */
+    pub fn set_topic_info(&mut self, setter: &str, time: i64) {
+        self.topic_setter = Some(setter.to_string());
+        self.topic_time = Some(time);
+    }
+
+    pub fn clear_topic(&mut self) {
+        self.topic = None;
+        self.topic_setter = None;
+        self.topic_time = None;
+    }
+
     pub fn num_users(&self) -> usize {
         return self.users.len();
     }
@@ -65,11 +193,84 @@ impl Channel {
 mod tests {
 
     use super::*;
+    use ircnvim::mode;
 
     #[test]
     fn test_is_channel_name() {
-        assert!("##c".to_string().is_channel_name());
-        assert!("&mychan".is_channel_name());
-        assert!("~otherchan".is_channel_name());
+        let mut info = ServerInfo::new();
+        assert!("##c".to_string().is_channel_name(&info));
+        assert!("&mychan".is_channel_name(&info));
+        assert!(!"~otherchan".is_channel_name(&info));
+
+        info.add_token("CHANTYPES=#+!~");
+        assert!("~otherchan".is_channel_name(&info));
+        assert!("+modeless".is_channel_name(&info));
+        assert!(!"&mychan".is_channel_name(&info));
+    }
+
+    #[test]
+    fn test_member_modes() {
+        let mut channel = Channel::new("#rust", CaseMapping::Rfc1459);
+        channel.add_member(User::from_nick("foo".to_string()), vec!['o', 'v']);
+        channel.set_member_mode("Foo", 'o', false);
+        channel.set_member_mode("foo", 'h', true);
+        channel.set_member_mode("foo", 'h', true);
+        assert_eq!(channel.member("foo").unwrap().modes, vec!['v', 'h']);
+
+        channel.rename(&User::from_nick("foo".to_string()), "bar");
+        assert_eq!(channel.member("BAR").unwrap().modes, vec!['v', 'h']);
+        assert_eq!(channel.member("bar").unwrap().user.nick, "bar");
+
+        channel.add_user(User::from_nick("bar".to_string()));
+        assert!(channel.member("bar").unwrap().modes.is_empty());
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut info = ServerInfo::new();
+        info.add_token("CHANMODES=beI,k,l,imnpst");
+        let mut channel = Channel::new("#rust", CaseMapping::Rfc1459);
+        channel.add_user(User::from_nick("foo".to_string()));
+
+        for change in mode::parse("+ntklbo", &["secret", "10", "*!*@spam", "foo"], &info) {
+            channel.apply_mode(&change);
+        }
+        assert_eq!(channel.mode_string(), "+klnt");
+        assert_eq!(channel.mode('k'), Some(Some("secret")));
+        assert_eq!(channel.mode('l'), Some(Some("10")));
+        assert_eq!(channel.mode('b'), None);
+        assert_eq!(channel.member("foo").unwrap().modes, vec!['o']);
+
+        for change in mode::parse("-k-l-t", &["secret"], &info) {
+            channel.apply_mode(&change);
+        }
+        assert_eq!(channel.mode_string(), "+n");
+
+        channel.clear_modes();
+        assert_eq!(channel.mode_string(), "");
+    }
+
+    #[test]
+    fn test_users_are_case_insensitive() {
+        let mut channel = Channel::new("#rust", CaseMapping::Rfc1459);
+        channel.add_user(User::from_nick("Foo[m]".to_string()));
+        channel.add_user(User::from_nick("foo{M}".to_string()));
+        assert_eq!(channel.num_users(), 1);
+        assert!(channel.is_user_present(&User::from_nick("FOO[M]".to_string())));
+
+        channel.rename(&User::from_nick("foo{m}".to_string()), "Bar");
+        assert!(!channel.is_user_present(&User::from_nick("Foo[m]".to_string())));
+        assert!(channel.is_user_present(&User::from_nick("bar".to_string())));
+
+        channel.remove_user(&User::from_nick("BAR".to_string()));
+        assert_eq!(channel.num_users(), 0);
+
+        /*
+         * Under ascii, brackets are no longer the same as braces.
+         */
+        channel.add_user(User::from_nick("Foo[m]".to_string()));
+        channel.set_casemapping(CaseMapping::Ascii);
+        assert!(channel.is_user_present(&User::from_nick("FOO[M]".to_string())));
+        assert!(!channel.is_user_present(&User::from_nick("foo{m}".to_string())));
     }
 }
@@ -1,22 +1,63 @@
+use ircnvim::cap::DEFAULT_CAPS;
+use ircnvim::ctcp;
+use ircnvim::lines;
+use ircnvim::sasl::Mechanism;
+use rustc_serialize::hex::FromHex;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::collections::HashMap;
+use std::net::IpAddr;
 
 #[derive(PartialEq, Eq)]
 pub enum AuthMethod {
     NoAuth,
     NickServ,
-    SASL
+    SASL,
+    External
+}
+
+/*
+ * How to secure the connection to the server. Without a pinned fingerprint the
+ * server's certificate is verified against the system roots (and `ca_file`).
+ */
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub ca_file: Option<String>,
+    pub fingerprint: Option<Vec<u8>>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>
 }
 
 pub struct Config {
     pub nick: String,
+    pub alt_nicks: Vec<String>,
     pub username: String,
     pub password: Option<String>,
     pub server: String,
+    pub host: String,
+    pub port: u16,
+    pub tls: Option<TlsConfig>,
     pub directory: String,
-    pub auth: AuthMethod
+    pub auth: AuthMethod,
+    pub sasl_mechanism: Option<Mechanism>,
+    pub nickserv_account: String,
+    pub caps: Vec<String>,
+    pub autojoin: Vec<String>,
+    pub hold_autojoin: bool,
+    pub rejoin_on_kick: bool,
+    pub ping_interval: u64,
+    pub ping_timeout: u64,
+    pub registration_timeout: u64,
+    pub auth_timeout: u64,
+    pub ctcp_version: Option<String>,
+    pub ctcp_userinfo: Option<String>,
+    pub dcc_directory: String,
+    pub dcc_address: Option<IpAddr>,
+    pub input_markup: bool,
+    pub max_line_length: usize,
+    pub flood_burst: u32,
+    pub flood_delay: u64
 }
 
 impl Config {
@@ -30,6 +71,9 @@ impl Config {
      * nick marchelzo
      * username marchelzo
      * password foobarbaz
+     * caps multi-prefix,server-time
+     * tls yes
+     * autojoin #rust,#neovim
      *
      * Lines beginning with # are comments and are ignored.
      */
@@ -51,7 +95,7 @@ impl Config {
                 if line.starts_with("#") { continue }
                 let option = option.trim_right();
                 if option.is_empty() { break }
-                match &option.split(" ").collect::<Vec<_>>()[..] {
+                match &option.splitn(2, ' ').collect::<Vec<_>>()[..] {
                     [key, val] => { p.insert(key.to_string(), val.to_string()); },
                     _          => return Err(format!("invalid option in configuration file: {}", option))
                 }
@@ -62,10 +106,10 @@ impl Config {
         }
 
 
-        let mut p = match profile {
+        let (name, mut p) = match profile {
             Some(name) => {
                 match profiles.remove(&name) {
-                    Some(p) => p,
+                    Some(p) => (name, p),
                     None    => return Err(format!("no profile named {} defined in configuration file", name))
                 }
             },
@@ -83,28 +127,321 @@ impl Config {
         }
 
         let nick = get_option!("nick");
+
+        /*
+         * Nicks to try, in order, if ours is taken when we connect.
+         */
+        let alt_nicks = match p.remove("alt_nicks") {
+            Some(s) => s.split(',').filter(|n| !n.is_empty()).map(|n| n.to_string()).collect(),
+            None    => Vec::new()
+        };
         let username = get_option!("username");
         let password = p.remove("password");
         let server   = get_option!("server");
+
         let auth = match p.remove("auth").map(|s| s.to_string().to_lowercase()).as_ref().map(|s| &s[..]) {
             None             => if password.is_some() { AuthMethod::SASL } else { AuthMethod::NoAuth },
             Some("nickserv") => AuthMethod::NickServ,
             Some("sasl")     => AuthMethod::SASL,
+            Some("external") => AuthMethod::External,
             Some("none")     => AuthMethod::NoAuth,
             Some(t)          => return Err(format!("invalid authentication method: {}", t))
         };
 
-        if auth != AuthMethod::NoAuth && password.is_none() {
+        if auth != AuthMethod::NoAuth && auth != AuthMethod::External && password.is_none() {
             return Err(format!("an authentication method was specified but no password was provided"));
         }
 
+        /*
+         * The SASL mechanism to use with auth sasl. By default, it's chosen based on
+         * what the server supports.
+         */
+        let sasl_mechanism = match p.remove("sasl_mechanism") {
+            Some(m) => match Mechanism::from_name(&m) {
+                Some(Mechanism::External) | None => return Err(format!("invalid SASL mechanism: {}", m)),
+                mechanism                        => mechanism
+            },
+            None    => None
+        };
+
+        let tls = match p.remove("tls") {
+            Some(v) => try!(parse_bool("tls", &v)),
+            None    => false
+        };
+
+        let tls_ca = p.remove("tls_ca").map(|f| in_directory(&directory, f));
+        let tls_fingerprint = match p.remove("tls_fingerprint") {
+            Some(f) => Some(try!(parse_fingerprint(&f))),
+            None    => None
+        };
+
+        /*
+         * The client certificate used for CertFP (auth external). By default, both the
+         * certificate and its key are read from <profile>.pem in the ircnvim directory.
+         */
+        let mut tls_cert = p.remove("tls_cert").map(|f| in_directory(&directory, f));
+        if tls_cert.is_none() && auth == AuthMethod::External {
+            tls_cert = Some(format!("{}/{}.pem", directory, name));
+        }
+        let tls_key = p.remove("tls_key").map(|f| in_directory(&directory, f)).or(tls_cert.clone());
+
+        if !tls && (tls_ca.is_some() || tls_fingerprint.is_some() || tls_cert.is_some()) {
+            return Err(format!("tls_ca, tls_fingerprint, tls_cert and auth external require tls yes"));
+        }
+
+        let tls = if tls {
+            Some(TlsConfig {
+                ca_file: tls_ca,
+                fingerprint: tls_fingerprint,
+                cert_file: tls_cert,
+                key_file: tls_key
+            })
+        } else {
+            None
+        };
+
+        /*
+         * The port is optional, and defaults to 6697 for TLS and 6667 otherwise.
+         */
+        let (host, port) = try!(parse_server(&server, if tls.is_some() { 6697 } else { 6667 }));
+
+        let nickserv_account = p.remove("nickserv_account").unwrap_or(username.clone());
+
+        let autojoin = match p.remove("autojoin") {
+            Some(s) => s.split(',').filter(|c| !c.is_empty()).map(|c| c.to_string()).collect(),
+            None    => Vec::new()
+        };
+
+        /*
+         * Whether to wait until NickServ has identified us before joining the
+         * autojoin channels, so that our cloak is already applied.
+         */
+        let hold_autojoin = match p.remove("hold_autojoin") {
+            Some(v) => try!(parse_bool("hold_autojoin", &v)),
+            None    => false
+        };
+
+        let rejoin_on_kick = match p.remove("rejoin_on_kick") {
+            Some(v) => try!(parse_bool("rejoin_on_kick", &v)),
+            None    => false
+        };
+
+        /*
+         * How often (in seconds) we PING the server to measure the lag (0 to never), and
+         * how long we wait without hearing anything from it before we reconnect.
+         */
+        let ping_interval = match p.remove("ping_interval") {
+            Some(v) => try!(parse_seconds("ping_interval", &v)),
+            None    => 60
+        };
+        let ping_timeout = match p.remove("ping_timeout") {
+            Some(v) => try!(parse_seconds("ping_timeout", &v)),
+            None    => 240
+        };
+
+        /*
+         * How long (in seconds) we wait for the server to complete our registration, and
+         * for SASL or NickServ to answer.
+         */
+        let registration_timeout = match p.remove("registration_timeout") {
+            Some(v) => try!(parse_seconds("registration_timeout", &v)),
+            None    => 30
+        };
+        let auth_timeout = match p.remove("auth_timeout") {
+            Some(v) => try!(parse_seconds("auth_timeout", &v)),
+            None    => 30
+        };
+
+        for &(option, value) in &[("ping_timeout", ping_timeout), ("registration_timeout", registration_timeout), ("auth_timeout", auth_timeout)] {
+            if value == 0 {
+                return Err(format!("{} must be at least 1 second", option));
+            }
+        }
+
+        /*
+         * What we answer to CTCP VERSION (or "none" to not answer it) and USERINFO.
+         */
+        let ctcp_version = match p.remove("ctcp_version") {
+            Some(ref v) if v == "none" => None,
+            Some(v)                    => Some(v),
+            None                       => Some(ctcp::default_version())
+        };
+        let ctcp_userinfo = p.remove("ctcp_userinfo");
+
+        /*
+         * Where files received over DCC are saved, and the address we ask others to
+         * connect to for our DCC offers (by default, the one we connect to the server from).
+         */
+        let dcc_directory = in_directory(&directory, p.remove("dcc_directory").unwrap_or("downloads".to_string()));
+        let dcc_address = match p.remove("dcc_address") {
+            Some(a) => match a.parse::<IpAddr>() {
+                Ok(address) => Some(address),
+                Err(_)      => return Err(format!("invalid value for dcc_address: {} (expected an IP address)", a))
+            },
+            None    => None
+        };
+
+        /*
+         * Whether %B, %C4 and so on in what we type are turned into formatting.
+         */
+        let input_markup = match p.remove("input_markup") {
+            Some(v) => try!(parse_bool("input_markup", &v)),
+            None    => false
+        };
+
+        /*
+         * The longest line we accept from the server (without CRLF). Anything longer is
+         * dropped with a warning.
+         */
+        let max_line_length = match p.remove("max_line_length") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(n) if n >= lines::MAX_MESSAGE_SIZE => n,
+                _                                     => return Err(format!("invalid value for max_line_length: {} (expected a number of bytes, at least {})", v, lines::MAX_MESSAGE_SIZE))
+            },
+            None    => lines::MAX_TAGS_SIZE + lines::MAX_MESSAGE_SIZE
+        };
+
+        /*
+         * How many lines we may send at once, and then how long (in milliseconds) we
+         * wait between lines, so that the server doesn't kill us for flooding. A delay
+         * of 0 turns this off.
+         */
+        let flood_burst = match p.remove("flood_burst") {
+            Some(v) => match v.parse::<u32>() {
+                Ok(n) if n > 0 => n,
+                _              => return Err(format!("invalid value for flood_burst: {} (expected a number of lines, at least 1)", v))
+            },
+            None    => 5
+        };
+        let flood_delay = match p.remove("flood_delay") {
+            Some(v) => match v.parse::<u64>() {
+                Ok(n)  => n,
+                Err(_) => return Err(format!("invalid value for flood_delay: {} (expected a number of milliseconds)", v))
+            },
+            None    => 2000
+        };
+
+        /*
+         * The IRCv3 capabilities to request, as a comma-separated list (or "none").
+         */
+        let mut caps = match p.remove("caps") {
+            Some(ref s) if s == "none" => Vec::new(),
+            Some(s)                    => s.split(',').filter(|c| !c.is_empty()).map(|c| c.to_string()).collect(),
+            None                       => DEFAULT_CAPS.iter().map(|c| c.to_string()).collect()
+        };
+
+        if (auth == AuthMethod::SASL || auth == AuthMethod::External) && !caps.iter().any(|c| c == "sasl") {
+            caps.push("sasl".to_string());
+        }
+
         return Ok(Config {
             nick: nick,
+            alt_nicks: alt_nicks,
             username: username,
             password: password,
             server: server,
+            host: host,
+            port: port,
+            tls: tls,
             directory: directory,
-            auth: auth
+            auth: auth,
+            sasl_mechanism: sasl_mechanism,
+            nickserv_account: nickserv_account,
+            caps: caps,
+            autojoin: autojoin,
+            hold_autojoin: hold_autojoin,
+            rejoin_on_kick: rejoin_on_kick,
+            ping_interval: ping_interval,
+            ping_timeout: ping_timeout,
+            registration_timeout: registration_timeout,
+            auth_timeout: auth_timeout,
+            ctcp_version: ctcp_version,
+            ctcp_userinfo: ctcp_userinfo,
+            dcc_directory: dcc_directory,
+            dcc_address: dcc_address,
+            input_markup: input_markup,
+            max_line_length: max_line_length,
+            flood_burst: flood_burst,
+            flood_delay: flood_delay
         });
     }
 }
+
+fn parse_bool(option: &str, value: &str) -> Result<bool, String> {
+    return match &value.to_lowercase()[..] {
+        "yes" | "true" | "on"  => Ok(true),
+        "no" | "false" | "off" => Ok(false),
+        _                      => Err(format!("invalid value for {}: {} (expected yes or no)", option, value))
+    };
+}
+
+fn parse_seconds(option: &str, value: &str) -> Result<u64, String> {
+    return match value.parse::<u64>() {
+        Ok(n)  => Ok(n),
+        Err(_) => Err(format!("invalid value for {}: {} (expected a number of seconds)", option, value))
+    };
+}
+
+/*
+ * Relative paths in the configuration file are relative to the ircnvim directory.
+ */
+fn in_directory(directory: &str, path: String) -> String {
+    if path.starts_with("/") {
+        return path;
+    } else {
+        return format!("{}/{}", directory, path);
+    }
+}
+
+/*
+ * Split a server address into host and port: host, host:port, or for IPv6
+ * addresses [address]:port, [address], or the bare address.
+ */
+fn parse_server(server: &str, default_port: u16) -> Result<(String, u16), String> {
+    let (host, port) = if server.starts_with("[") {
+        match server.find(']') {
+            Some(i) if i + 1 == server.len()          => (&server[1..i], None),
+            Some(i) if server[i+1..].starts_with(":") => (&server[1..i], Some(&server[i+2..])),
+            _                                         => return Err(format!("invalid server address: {}", server))
+        }
+    } else {
+        match server.rfind(':') {
+            Some(i) if server[..i].find(':').is_none() => (&server[..i], Some(&server[i+1..])),
+            _                                          => (server, None)
+        }
+    };
+
+    return match port.map(|port| port.parse::<u16>()) {
+        Some(Ok(port)) => Ok((host.to_string(), port)),
+        Some(Err(_))   => Err(format!("invalid port in server address: {}", server)),
+        None           => Ok((host.to_string(), default_port))
+    };
+}
+
+/*
+ * Parse a SHA-256 fingerprint given in hex, optionally with colons between the bytes.
+ */
+fn parse_fingerprint(fingerprint: &str) -> Result<Vec<u8>, String> {
+    return match fingerprint.replace(":", "").from_hex() {
+        Ok(ref bytes) if bytes.len() == 32 => Ok(bytes.clone()),
+        _                                  => Err(format!("invalid SHA-256 fingerprint: {}", fingerprint))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_server() {
+        assert_eq!(parse_server("irc.test", 6667), Ok(("irc.test".to_string(), 6667)));
+        assert_eq!(parse_server("irc.test:7000", 6667), Ok(("irc.test".to_string(), 7000)));
+        assert_eq!(parse_server("::1", 6667), Ok(("::1".to_string(), 6667)));
+        assert_eq!(parse_server("[::1]", 6697), Ok(("::1".to_string(), 6697)));
+        assert_eq!(parse_server("[2001:db8::1]:7000", 6667), Ok(("2001:db8::1".to_string(), 7000)));
+        assert!(parse_server("irc.test:port", 6667).is_err());
+        assert!(parse_server("[::1]7000", 6667).is_err());
+        assert!(parse_server("[::1", 6667).is_err());
+    }
+}
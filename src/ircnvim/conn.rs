@@ -0,0 +1,543 @@
+use ircnvim::config::TlsConfig;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use rustc_serialize::hex::ToHex;
+use std::cmp::min;
+use std::io::Read;
+use std::io::Write;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/*
+ * How long the I/O thread of a TLS connection blocks on a read before checking
+ * whether there is anything to write.
+ */
+const POLL_INTERVAL_MS: u64 = 50;
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+/*
+ * How long connecting, and then the TLS handshake, may take before we give up.
+ */
+const CONNECT_TIMEOUT_S: u64 = 30;
+
+/*
+ * The writing half of a connection to an IRC server.
+ *
+ * Bytes written to a Connection are passed to a writer thread, which blocks
+ * until there is something to write, while a reader thread blocks on the
+ * socket. A TLS stream can't be read from one thread while another writes to
+ * it, so there a single I/O thread owns it, and writes between reads that time
+ * out after POLL_INTERVAL_MS. That delays what we send by up to that long,
+ * which is of no consequence next to the flood control lines go through.
+ */
+pub struct Connection {
+    outgoing: Sender<Vec<u8>>,
+    thread: thread::JoinHandle<()>,
+    local_addr: Option<SocketAddr>
+}
+
+/*
+ * The reading half of a connection to an IRC server. It yields the bytes
+ * read by the I/O thread, and an error once the connection is lost.
+ */
+pub struct Reader {
+    incoming: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    deadline: Option<Instant>
+}
+
+impl Connection {
+    /*
+     * Connect to host:port, over TLS if `tls` is given.
+     */
+    pub fn open(host: &str, port: u16, tls: Option<&TlsConfig>) -> Result<(Connection, Reader), String> {
+        let tcp = try!(connect(host, port));
+
+        let local_addr = tcp.local_addr().ok();
+
+        let (outgoing_tx, outgoing_rx) = channel();
+        let (incoming_tx, incoming_rx) = channel();
+
+        let thread = match tls {
+            Some(tls) => {
+                let stream = try!(tls_handshake(host, tcp, tls));
+
+                /*
+                 * This has to happen after the TLS handshake, which has timeouts of its own.
+                 */
+                let timeout = Some(Duration::from_millis(POLL_INTERVAL_MS));
+                let result = stream.get_ref().set_read_timeout(timeout).and_then(|()| stream.get_ref().set_write_timeout(None));
+                if let Err(e) = result {
+                    return Err(e.to_string());
+                }
+
+                thread::spawn(move || io_loop(stream, outgoing_rx, incoming_tx))
+            },
+            None      => {
+                let mut reading = try!(tcp.try_clone().map_err(|e| e.to_string()));
+                let reader_tx = incoming_tx.clone();
+                thread::spawn(move || {
+                    let mut buf = [0u8; READ_CHUNK_SIZE];
+                    while pass_on(&mut reading, &mut buf, &reader_tx) { }
+                });
+                thread::spawn(move || write_loop(tcp, outgoing_rx, incoming_tx))
+            }
+        };
+
+        let connection = Connection {
+            outgoing: outgoing_tx,
+            thread: thread,
+            local_addr: local_addr
+        };
+
+        let reader = Reader {
+            incoming: incoming_rx,
+            chunk: Vec::new(),
+            pos: 0,
+            deadline: None
+        };
+
+        return Ok((connection, reader));
+    }
+
+    /*
+     * Our end of the connection, e.g. the address to offer DCC connections on.
+     */
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        return self.local_addr;
+    }
+
+    /*
+     * Close the connection, once everything that was written to it has been sent.
+     */
+    pub fn close(self) {
+        let Connection { outgoing, thread, .. } = self;
+        drop(outgoing);
+        let _ = thread.join();
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return match self.outgoing.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))
+        };
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Reader {
+    /*
+     * Make reads fail with ErrorKind::TimedOut if nothing arrives before `deadline`.
+     */
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let next = match self.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+                }
+                match self.incoming.recv_timeout(deadline - now) {
+                    Ok(next)                            => Some(next),
+                    Err(RecvTimeoutError::Timeout)      => return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+                    Err(RecvTimeoutError::Disconnected) => None
+                }
+            },
+            None           => self.incoming.recv().ok()
+        };
+
+        return match next {
+            Some(next) => next,
+            None       => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed"))
+        };
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            self.chunk = try!(self.next_chunk());
+            self.pos = 0;
+        }
+
+        let n = min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+
+        return Ok(n);
+    }
+}
+
+/*
+ * Connect to the first address of `host` that answers within CONNECT_TIMEOUT_S.
+ */
+fn connect(host: &str, port: u16) -> Result<TcpStream, String> {
+    let addrs = try!((host, port).to_socket_addrs().map_err(|e| e.to_string()));
+    let mut error = format!("no address found for {}", host);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECT_TIMEOUT_S)) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e)  => error = format!("{}: {}", addr, e)
+        }
+    }
+    return Err(error);
+}
+
+/*
+ * Perform the TLS handshake over `tcp`. A server that stops answering halfway
+ * through makes it fail after CONNECT_TIMEOUT_S.
+ *
+ * If a fingerprint is pinned, the usual chain and hostname verification is
+ * skipped, and instead the SHA-256 digest of the server's certificate has to
+ * match it exactly. This lets us use self-signed certificates on private networks.
+ */
+fn tls_handshake(host: &str, tcp: TcpStream, tls: &TlsConfig) -> Result<SslStream<TcpStream>, String> {
+    let mut builder = try!(SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string()));
+
+    if let Some(ref ca_file) = tls.ca_file {
+        if let Err(e) = builder.set_ca_file(ca_file) {
+            return Err(format!("couldn't load CA file {}: {}", ca_file, e));
+        }
+    }
+
+    if tls.fingerprint.is_some() {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+
+    /*
+     * Present a client certificate, so that the server can identify us by its fingerprint.
+     */
+    if let (&Some(ref cert_file), &Some(ref key_file)) = (&tls.cert_file, &tls.key_file) {
+        if let Err(e) = builder.set_certificate_chain_file(cert_file) {
+            return Err(format!("couldn't load client certificate {}: {}", cert_file, e));
+        }
+        if let Err(e) = builder.set_private_key_file(key_file, SslFiletype::PEM) {
+            return Err(format!("couldn't load client key {}: {}", key_file, e));
+        }
+        if let Err(e) = builder.check_private_key() {
+            return Err(format!("client key {} does not match certificate {}: {}", key_file, cert_file, e));
+        }
+    }
+
+    let timeout = Some(Duration::from_secs(CONNECT_TIMEOUT_S));
+    try!(tcp.set_read_timeout(timeout).and_then(|()| tcp.set_write_timeout(timeout)).map_err(|e| e.to_string()));
+
+    let mut configuration = try!(builder.build().configure().map_err(|e| e.to_string()));
+    configuration.set_verify_hostname(tls.fingerprint.is_none());
+
+    let stream = match configuration.connect(host, tcp) {
+        Ok(stream) => stream,
+        Err(e)     => return Err(format!("TLS handshake with {} failed: {}", host, e))
+    };
+
+    if let Some(ref fingerprint) = tls.fingerprint {
+        let digest = match stream.ssl().peer_certificate().map(|cert| cert.digest(MessageDigest::sha256())) {
+            Some(Ok(digest)) => digest,
+            Some(Err(e))     => return Err(e.to_string()),
+            None             => return Err(format!("{} did not present a certificate", host))
+        };
+        if &digest[..] != &fingerprint[..] {
+            return Err(format!(
+                "the certificate presented by {} has fingerprint {}, expected {}",
+                host,
+                digest.to_hex(),
+                fingerprint.to_hex()
+            ));
+        }
+    }
+
+    return Ok(stream);
+}
+
+/*
+ * Owns a TLS stream: writes whatever the Connection sends us, and passes
+ * whatever we read on to the Reader. The thread exits when either side
+ * goes away or the connection fails. If the Connection is dropped, what
+ * it sent before that is still written out.
+ */
+fn io_loop<S: Read + Write>(mut stream: S, outgoing: Receiver<Vec<u8>>, incoming: Sender<io::Result<Vec<u8>>>) {
+    let mut buf = [0u8; READ_CHUNK_SIZE];
+    loop {
+        loop {
+            match outgoing.try_recv() {
+                Ok(bytes)                       => {
+                    if !write_out(&mut stream, &bytes[..], &incoming) { return }
+                },
+                Err(TryRecvError::Empty)        => break,
+                Err(TryRecvError::Disconnected) => return
+            }
+        }
+
+        if !pass_on(&mut stream, &mut buf, &incoming) { return }
+    }
+}
+
+/*
+ * Writes whatever the Connection sends us to a plain TCP stream. Once the
+ * Connection is dropped and what it sent has been written, or writing fails,
+ * the socket is shut down, which also ends the reader thread.
+ */
+fn write_loop(mut tcp: TcpStream, outgoing: Receiver<Vec<u8>>, incoming: Sender<io::Result<Vec<u8>>>) {
+    for bytes in outgoing.iter() {
+        if !write_out(&mut tcp, &bytes[..], &incoming) { break }
+    }
+    let _ = tcp.shutdown(Shutdown::Both);
+}
+
+/*
+ * Read from `stream` once, and pass what was read on to the Reader. Returns
+ * false once there is nothing more to read, or nobody left to read it.
+ */
+fn pass_on<R: Read>(stream: &mut R, buf: &mut [u8], incoming: &Sender<io::Result<Vec<u8>>>) -> bool {
+    return match stream.read(buf) {
+        Ok(0)  => {
+            let _ = incoming.send(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server")));
+            false
+        },
+        Ok(n)  => incoming.send(Ok(buf[..n].to_vec())).is_ok(),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => true,
+        Err(e) => {
+            let _ = incoming.send(Err(e));
+            false
+        }
+    };
+}
+
+/*
+ * Write `bytes` to `stream`, passing any error on to the Reader. Returns
+ * whether it worked.
+ */
+fn write_out<W: Write>(stream: &mut W, bytes: &[u8], incoming: &Sender<io::Result<Vec<u8>>>) -> bool {
+    if let Err(e) = stream.write_all(bytes) {
+        let _ = incoming.send(Err(e));
+        return false;
+    }
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ircnvim::config::TlsConfig;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslAcceptor, SslMethod};
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509, X509Name};
+    use std::env;
+    use std::fs::File;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn self_signed() -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().dns("localhost").build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        return (builder.build(), key);
+    }
+
+    /*
+     * A minimal TLS IRC server: greet the client, then echo back the first line it sends.
+     * Returns the port it listens on.
+     */
+    fn stand_in(cert: &X509, key: &PKey<Private>) -> u16 {
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(cert).unwrap();
+        acceptor.set_private_key(key).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let tcp = listener.accept().unwrap().0;
+            let mut stream = match acceptor.accept(tcp) {
+                Ok(stream) => stream,
+                Err(_)     => return
+            };
+            stream.write_all(b":irc.test 001 nick :Welcome to the test network\r\n").unwrap();
+            let mut line = String::new();
+            BufReader::new(&mut stream).read_line(&mut line).unwrap();
+            stream.write_all(line.as_bytes()).unwrap();
+        });
+
+        return port;
+    }
+
+    fn read_until(reader: &mut Reader, needle: &str) -> String {
+        let mut received = String::new();
+        let mut buf = [0u8; 256];
+        while !received.contains(needle) {
+            let n = reader.read(&mut buf).unwrap();
+            received.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        return received;
+    }
+
+    #[test]
+    fn test_tls_pinned_fingerprint() {
+        let (cert, key) = self_signed();
+        let port = stand_in(&cert, &key);
+        let tls = TlsConfig {
+            ca_file: None,
+            fingerprint: Some(cert.digest(MessageDigest::sha256()).unwrap().to_vec()),
+            cert_file: None,
+            key_file: None
+        };
+
+        let (mut connection, mut reader) = Connection::open("localhost", port, Some(&tls)).unwrap();
+        read_until(&mut reader, "Welcome");
+        connection.write_all(b"PING :hello\r\n").unwrap();
+        read_until(&mut reader, "PING :hello");
+    }
+
+    #[test]
+    fn test_tls_wrong_fingerprint() {
+        let (cert, key) = self_signed();
+        let port = stand_in(&cert, &key);
+        let tls = TlsConfig {
+            ca_file: None,
+            fingerprint: Some(vec![0u8; 32]),
+            cert_file: None,
+            key_file: None
+        };
+
+        assert!(Connection::open("localhost", port, Some(&tls)).is_err());
+    }
+
+    #[test]
+    fn test_tls_untrusted_certificate() {
+        let (cert, key) = self_signed();
+        let port = stand_in(&cert, &key);
+        let tls = TlsConfig {
+            ca_file: None,
+            fingerprint: None,
+            cert_file: None,
+            key_file: None
+        };
+
+        assert!(Connection::open("localhost", port, Some(&tls)).is_err());
+    }
+
+    #[test]
+    fn test_tls_ca_file() {
+        let (cert, key) = self_signed();
+        let port = stand_in(&cert, &key);
+
+        let path = env::temp_dir().join(format!("ircnvim-test-ca-{}.pem", port));
+        File::create(&path).unwrap().write_all(&cert.to_pem().unwrap()[..]).unwrap();
+        let tls = TlsConfig {
+            ca_file: Some(path.to_str().unwrap().to_string()),
+            fingerprint: None,
+            cert_file: None,
+            key_file: None
+        };
+
+        /*
+         * The CA file is only needed for the handshake.
+         */
+        let result = Connection::open("localhost", port, Some(&tls));
+        fs::remove_file(&path).unwrap();
+
+        let (_connection, mut reader) = result.unwrap();
+        read_until(&mut reader, "Welcome");
+    }
+
+    #[test]
+    fn test_plain() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut tcp = listener.accept().unwrap().0;
+            tcp.write_all(b":irc.test 001 nick :Welcome\r\n").unwrap();
+        });
+
+        let (_connection, mut reader) = Connection::open("127.0.0.1", port, None).unwrap();
+        read_until(&mut reader, "Welcome");
+
+        /*
+         * The server hung up after greeting us.
+         */
+        let mut buf = [0u8; 16];
+        assert!(reader.read(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_close_sends_pending() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut tcp = listener.accept().unwrap().0;
+            let mut received = String::new();
+            tcp.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        let (mut connection, _reader) = Connection::open("127.0.0.1", port, None).unwrap();
+        connection.write_all(b"QUIT :bye\r\n").unwrap();
+        connection.close();
+
+        assert_eq!(server.join().unwrap(), "QUIT :bye\r\n");
+    }
+
+    #[test]
+    fn test_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (go, wait) = channel();
+        thread::spawn(move || {
+            let mut tcp = listener.accept().unwrap().0;
+            wait.recv().unwrap();
+            tcp.write_all(b"PING :late\r\n").unwrap();
+            wait.recv().unwrap_or(());
+        });
+
+        let (_connection, mut reader) = Connection::open("127.0.0.1", port, None).unwrap();
+        let mut buf = [0u8; 64];
+
+        reader.set_deadline(Some(Instant::now() + Duration::from_millis(100)));
+        match reader.read(&mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => { },
+            other                                             => panic!("expected a timeout, got {:?}", other)
+        }
+
+        /*
+         * The connection is still usable afterwards.
+         */
+        reader.set_deadline(None);
+        go.send(()).unwrap();
+        read_until(&mut reader, "late");
+    }
+}
@@ -0,0 +1,120 @@
+use std::ascii::AsciiExt;
+use std::collections::VecDeque;
+use time;
+
+/*
+ * The queries we answer, for CLIENTINFO.
+ */
+const SUPPORTED: &'static str = "ACTION CLIENTINFO DCC PING SOURCE TIME USERINFO VERSION";
+
+const SOURCE: &'static str = "https://github.com/marchelzo/ircnvim-rs";
+
+/*
+ * We answer at most MAX_REPLIES queries every REPLY_WINDOW_NS nanoseconds, so that a
+ * flood of them can't get us disconnected for flooding the server in turn.
+ */
+const MAX_REPLIES: usize = 4;
+const REPLY_WINDOW_NS: u64 = 10 * 1000000000;
+
+pub fn default_version() -> String {
+    return format!("ircnvim-rs {}", env!("CARGO_PKG_VERSION"));
+}
+
+/*
+ * Split the body of a CTCP message (without the \x01 delimiters) into its command,
+ * in upper case, and its arguments.
+ */
+pub fn parse(body: &str) -> (String, &str) {
+    return match body.find(' ') {
+        Some(i) => (body[..i].to_ascii_uppercase(), &body[i+1..]),
+        None    => (body.to_ascii_uppercase(), "")
+    };
+}
+
+/*
+ * Frame a CTCP message to be sent as a PRIVMSG (query) or NOTICE (reply).
+ */
+pub fn encode(command: &str, args: &str) -> String {
+    if args.is_empty() {
+        return format!("\x01{}\x01", command);
+    } else {
+        return format!("\x01{} {}\x01", command, args);
+    }
+}
+
+/*
+ * The arguments of our reply to a query, or None if we don't answer it.
+ */
+pub fn answer(command: &str, args: &str, version: Option<&str>, userinfo: Option<&str>) -> Option<String> {
+    return match command {
+        "VERSION"    => version.map(|v| v.to_string()),
+        "PING"       => Some(args.to_string()),
+        "TIME"       => Some(time::now().rfc822z().to_string()),
+        "CLIENTINFO" => Some(SUPPORTED.to_string()),
+        "SOURCE"     => Some(SOURCE.to_string()),
+        "USERINFO"   => userinfo.map(|u| u.to_string()),
+        _            => None
+    };
+}
+
+pub struct RateLimit {
+    replies: VecDeque<u64>
+}
+
+impl RateLimit {
+    pub fn new() -> RateLimit {
+        return RateLimit { replies: VecDeque::new() };
+    }
+
+    /*
+     * Whether we may send a reply at time `now` (in nanoseconds). If so, it's counted.
+     */
+    pub fn allow(&mut self, now: u64) -> bool {
+        while self.replies.front().map(|&t| now - t >= REPLY_WINDOW_NS).unwrap_or(false) {
+            self.replies.pop_front();
+        }
+
+        if self.replies.len() >= MAX_REPLIES {
+            return false;
+        }
+
+        self.replies.push_back(now);
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("version"), ("VERSION".to_string(), ""));
+        assert_eq!(parse("PING 12345 678"), ("PING".to_string(), "12345 678"));
+    }
+
+    #[test]
+    fn test_answer() {
+        assert_eq!(answer("VERSION", "", Some("foo 1.0"), None), Some("foo 1.0".to_string()));
+        assert_eq!(answer("VERSION", "", None, None), None);
+        assert_eq!(answer("PING", "12345", None, None), Some("12345".to_string()));
+        assert_eq!(answer("USERINFO", "", None, None), None);
+        assert_eq!(answer("DCC", "CHAT chat 0 0", None, None), None);
+        assert_eq!(encode("PING", "12345"), "\x01PING 12345\x01");
+        assert_eq!(encode("VERSION", ""), "\x01VERSION\x01");
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limit = RateLimit::new();
+        let second = 1000000000;
+        for i in 0..MAX_REPLIES as u64 {
+            assert!(limit.allow(i * second));
+        }
+        assert!(!limit.allow(5 * second));
+        assert!(limit.allow(REPLY_WINDOW_NS));
+        assert!(!limit.allow(REPLY_WINDOW_NS + second / 2));
+        assert!(limit.allow(REPLY_WINDOW_NS + second));
+    }
+}
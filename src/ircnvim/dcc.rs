@@ -0,0 +1,779 @@
+use std::ascii::AsciiExt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHUNK_SIZE: usize = 8192;
+
+/*
+ * How often the transfer threads check whether they've been cancelled.
+ */
+const POLL_INTERVAL_MS: u64 = 100;
+
+/*
+ * How long our offers wait for the other side to connect, and how long we wait for
+ * the receiver to acknowledge the end of a file we've sent.
+ */
+const OFFER_TIMEOUT_S: u64 = 300;
+const ACK_TIMEOUT_S: u64 = 30;
+
+/*
+ * How many offers from others we keep track of at once; any more are ignored.
+ */
+pub const MAX_OFFERS: usize = 10;
+
+/*
+ * A DCC request, carried by a CTCP DCC query.
+ */
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    Chat(SocketAddr),
+    Send { file: String, addr: SocketAddr, size: Option<u64> },
+    Resume { file: String, port: u16, position: u64 },
+    Accept { file: String, port: u16, position: u64 }
+}
+
+/*
+ * What the transfer threads tell the session.
+ */
+pub enum Event {
+    Connected(usize, Option<TcpStream>),
+    Progress(usize, u64),
+    Line(usize, String),
+    Closed(usize, Result<(), String>)
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Kind {
+    Chat,
+    Send,
+    Receive
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum State {
+    Offered,  // they made an offer, which we haven't accepted yet
+    Resuming, // we asked to resume the file, and wait for them to agree
+    Waiting,  // we made an offer, and wait for them to connect
+    Active
+}
+
+pub struct Transfer {
+    pub id: usize,
+    pub nick: String,
+    pub kind: Kind,
+    pub state: State,
+    pub file_name: String,
+    pub path: String,
+    pub size: Option<u64>,
+    pub position: u64,
+    pub addr: Option<SocketAddr>,
+    pub port: u16,
+    pub offset: Arc<Mutex<u64>>,
+    pub cancel: Arc<AtomicBool>,
+    pub writer: Option<TcpStream>,
+    pub since: Instant  // when the offer was made, or we asked to resume it
+}
+
+impl Transfer {
+    pub fn new(nick: &str, kind: Kind, state: State) -> Transfer {
+        return Transfer {
+            id: 0,
+            nick: nick.to_string(),
+            kind: kind,
+            state: state,
+            file_name: String::new(),
+            path: String::new(),
+            size: None,
+            position: 0,
+            addr: None,
+            port: 0,
+            offset: Arc::new(Mutex::new(0)),
+            cancel: Arc::new(AtomicBool::new(false)),
+            writer: None,
+            since: Instant::now()
+        };
+    }
+
+    /*
+     * Stop the transfer (or chat). Its thread reports it as closed soon after.
+     */
+    pub fn close(&mut self) {
+        self.cancel.store(true, Ordering::SeqCst);
+        if let Some(ref writer) = self.writer {
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let what = match self.kind {
+            Kind::Chat    => format!("chat with {}", self.nick),
+            Kind::Send    => format!("{} to {}", self.file_name, self.nick),
+            Kind::Receive => format!("{} from {}", self.file_name, self.nick)
+        };
+        let state = match self.state {
+            State::Offered  => "offered".to_string(),
+            State::Resuming => "resuming".to_string(),
+            State::Waiting  => "waiting".to_string(),
+            State::Active   => match (self.kind, self.size) {
+                (Kind::Chat, _)    => "connected".to_string(),
+                (_, Some(size))    => format!("{}/{} bytes", self.position, size),
+                (_, None)          => format!("{} bytes", self.position)
+            }
+        };
+        return format!("{}: {} ({})", self.id, what, state);
+    }
+
+    /*
+     * e.g., "log.txt 45%", for the status line.
+     */
+    pub fn progress(&self) -> Option<String> {
+        if self.kind == Kind::Chat || self.state != State::Active {
+            return None;
+        }
+        return match self.size {
+            Some(size) if size > 0 => Some(format!("{} {}%", self.file_name, self.position * 100 / size)),
+            _                      => Some(format!("{} {}B", self.file_name, self.position))
+        };
+    }
+}
+
+/*
+ * The DCC chats and file transfers, in progress or offered.
+ */
+pub struct Dcc {
+    transfers: Vec<Transfer>,
+    next_id: usize,
+    events: Receiver<Event>,
+    sender: Sender<Event>
+}
+
+impl Dcc {
+    pub fn new() -> Dcc {
+        let (sender, events) = channel();
+        return Dcc {
+            transfers: Vec::new(),
+            next_id: 1,
+            events: events,
+            sender: sender
+        };
+    }
+
+    pub fn add(&mut self, mut transfer: Transfer) -> usize {
+        transfer.id = self.next_id;
+        self.next_id += 1;
+        self.transfers.push(transfer);
+        return self.next_id - 1;
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Transfer> {
+        return self.transfers.iter_mut().find(|t| t.id == id);
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Transfer> {
+        return match self.transfers.iter().position(|t| t.id == id) {
+            Some(i) => Some(self.transfers.remove(i)),
+            None    => None
+        };
+    }
+
+    /*
+     * The transfer with `nick` that a RESUME or ACCEPT for `port` is about.
+     */
+    pub fn find(&self, nick: &str, kind: Kind, state: State, port: u16) -> Option<usize> {
+        return self.transfers
+            .iter()
+            .find(|t| t.kind == kind && t.state == state && t.port == port && t.nick.eq_ignore_ascii_case(nick))
+            .map(|t| t.id);
+    }
+
+    /*
+     * The offer with the given id, or the latest one.
+     */
+    pub fn find_offered(&self, id: Option<usize>) -> Option<usize> {
+        return self.transfers
+            .iter()
+            .rev()
+            .find(|t| t.state == State::Offered && id.map(|id| t.id == id).unwrap_or(true))
+            .map(|t| t.id);
+    }
+
+    /*
+     * How many offers from others are waiting for us to accept or reject them.
+     */
+    pub fn offered(&self) -> usize {
+        return self.transfers.iter().filter(|t| t.state == State::Offered).count();
+    }
+
+    /*
+     * Remove and return the offers nobody acted on within OFFER_TIMEOUT_S, whether
+     * they're theirs waiting for us, or our resume requests waiting for them.
+     */
+    pub fn expire(&mut self, now: Instant) -> Vec<Transfer> {
+        let timeout = Duration::from_secs(OFFER_TIMEOUT_S);
+        let (expired, kept) = self.transfers.drain(..).partition(|t| {
+            (t.state == State::Offered || t.state == State::Resuming) && now >= t.since + timeout
+        });
+        self.transfers = kept;
+        return expired;
+    }
+
+    /*
+     * The established chat with `nick`.
+     */
+    pub fn chat(&mut self, nick: &str) -> Option<&mut Transfer> {
+        return self.transfers
+            .iter_mut()
+            .find(|t| t.kind == Kind::Chat && t.writer.is_some() && t.nick.eq_ignore_ascii_case(nick));
+    }
+
+    pub fn transfers(&self) -> &[Transfer] {
+        return &self.transfers[..];
+    }
+
+    pub fn sender(&self) -> Sender<Event> {
+        return self.sender.clone();
+    }
+
+    pub fn poll(&self) -> Option<Event> {
+        return self.events.try_recv().ok();
+    }
+}
+
+/*
+ * Parse the arguments of a CTCP DCC query, e.g.,
+ *
+ * SEND "some file.txt" 3232235777 5000 1234
+ *
+ * Passive (reverse) DCC, where the port is 0, isn't supported.
+ */
+pub fn parse(args: &str) -> Option<Request> {
+    let (kind, rest) = match args.find(' ') {
+        Some(i) => (args[..i].to_ascii_uppercase(), &args[i+1..]),
+        None    => return None
+    };
+
+    let (name, rest) = match split_file_name(rest) {
+        Some(split) => split,
+        None        => return None
+    };
+    let words = rest.split_whitespace().collect::<Vec<_>>();
+
+    return match (&kind[..], &words[..]) {
+        ("CHAT", &[ip, port, ..])                          => parse_addr(ip, port).map(Request::Chat),
+        ("SEND", &[ip, port])                              => parse_addr(ip, port).map(|addr| {
+            Request::Send { file: name, addr: addr, size: None }
+        }),
+        ("SEND", &[ip, port, size, ..])                    => parse_addr(ip, port).map(|addr| {
+            Request::Send { file: name, addr: addr, size: size.parse().ok() }
+        }),
+        ("RESUME", &[port, position, ..])                  => match (port.parse(), position.parse()) {
+            (Ok(port), Ok(position)) => Some(Request::Resume { file: name, port: port, position: position }),
+            _                        => None
+        },
+        ("ACCEPT", &[port, position, ..])                  => match (port.parse(), position.parse()) {
+            (Ok(port), Ok(position)) => Some(Request::Accept { file: name, port: port, position: position }),
+            _                        => None
+        },
+        _                                                  => None
+    };
+}
+
+/*
+ * A file name is quoted if it contains spaces.
+ */
+fn split_file_name(s: &str) -> Option<(String, &str)> {
+    if s.starts_with('"') {
+        return s[1..].find('"').map(|i| (s[1..i+1].to_string(), &s[i+2..]));
+    }
+    return match s.find(' ') {
+        Some(i) => Some((s[..i].to_string(), &s[i..])),
+        None    => None
+    };
+}
+
+/*
+ * IPv4 addresses are sent as a single (decimal) integer, IPv6 addresses as they are.
+ */
+fn parse_addr(ip: &str, port: &str) -> Option<SocketAddr> {
+    let ip = match ip.parse::<u32>() {
+        Ok(n)  => IpAddr::V4(Ipv4Addr::from(n)),
+        Err(_) => match ip.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return None
+        }
+    };
+    return match port.parse::<u16>() {
+        Ok(0) | Err(_) => None,
+        Ok(port)       => Some(SocketAddr::new(ip, port))
+    };
+}
+
+pub fn encode_ip(ip: IpAddr) -> String {
+    return match ip {
+        IpAddr::V4(ip) => u32::from(ip).to_string(),
+        IpAddr::V6(ip) => ip.to_string()
+    };
+}
+
+pub fn quote_file_name(name: &str) -> String {
+    if name.contains(' ') {
+        return format!("\"{}\"", name);
+    } else {
+        return name.to_string();
+    }
+}
+
+/*
+ * Never let the sender choose where the file goes: keep only the last component of
+ * the name, and don't let it be hidden.
+ */
+pub fn sanitize_file_name(name: &str) -> String {
+    let name = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("");
+    let name = name.trim_left_matches('.');
+    if name.is_empty() {
+        return "download".to_string();
+    } else {
+        return name.to_string();
+    }
+}
+
+/*
+ * `path`, or if something is already there, the first of `path`.1, `path`.2, ...
+ * that is free.
+ */
+pub fn unique_path(path: &str) -> String {
+    let mut candidate = path.to_string();
+    let mut n = 0;
+    while Path::new(&candidate).exists() {
+        n += 1;
+        candidate = format!("{}.{}", path, n);
+    }
+    return candidate;
+}
+
+/*
+ * Open the file a download goes to, keeping only its first `offset` bytes. A new
+ * download (at offset 0) never overwrites an existing file.
+ */
+pub fn open_download(path: &str, offset: u64) -> io::Result<File> {
+    if offset == 0 {
+        return OpenOptions::new().write(true).create_new(true).open(path);
+    }
+    let mut file = try!(OpenOptions::new().write(true).open(path));
+    try!(file.set_len(offset));
+    try!(file.seek(SeekFrom::End(0)));
+    return Ok(file);
+}
+
+/*
+ * Connect to a chat offer, and read lines from it until it closes.
+ */
+pub fn start_chat(addr: SocketAddr, id: usize, events: Sender<Event>) {
+    thread::spawn(move || {
+        match TcpStream::connect(addr).and_then(|s| s.try_clone().map(|w| (s, w))) {
+            Ok((stream, writer)) => {
+                let _ = events.send(Event::Connected(id, Some(writer)));
+                chat_loop(stream, id, events);
+            },
+            Err(e)               => { let _ = events.send(Event::Closed(id, Err(e.to_string()))); }
+        }
+    });
+}
+
+/*
+ * Wait for the other side of our chat offer to connect.
+ */
+pub fn start_chat_offer(listener: TcpListener, id: usize, events: Sender<Event>, cancel: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        match accept(&listener, &cancel).and_then(|s| s.try_clone().map(|w| (s, w)).map_err(|e| e.to_string())) {
+            Ok((stream, writer)) => {
+                let _ = events.send(Event::Connected(id, Some(writer)));
+                chat_loop(stream, id, events);
+            },
+            Err(e)               => { let _ = events.send(Event::Closed(id, Err(e))); }
+        }
+    });
+}
+
+fn chat_loop(stream: TcpStream, id: usize, events: Sender<Event>) {
+    for line in BufReader::new(stream).split(b'\n') {
+        match line {
+            Ok(mut line) => {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                let line = String::from_utf8_lossy(&line).into_owned();
+                if events.send(Event::Line(id, line)).is_err() { return }
+            },
+            Err(e)       => {
+                let _ = events.send(Event::Closed(id, Err(e.to_string())));
+                return;
+            }
+        }
+    }
+    let _ = events.send(Event::Closed(id, Ok(())));
+}
+
+/*
+ * Send a line over a chat connection.
+ */
+pub fn write_line(writer: &mut TcpStream, line: &str) -> io::Result<()> {
+    return writer.write_all(format!("{}\n", line).as_bytes());
+}
+
+/*
+ * Connect to a file offer and write what it sends to `file`, which already holds the
+ * first `offset` bytes of it.
+ */
+pub fn start_receive(addr: SocketAddr, file: File, offset: u64, size: Option<u64>, id: usize, events: Sender<Event>, cancel: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let result = match TcpStream::connect(addr) {
+            Ok(stream) => {
+                let _ = events.send(Event::Connected(id, None));
+                receive_loop(stream, file, offset, size, id, &events, &cancel)
+            },
+            Err(e)     => Err(e.to_string())
+        };
+        let _ = events.send(Event::Closed(id, result));
+    });
+}
+
+fn receive_loop(mut stream: TcpStream, mut file: File, offset: u64, size: Option<u64>, id: usize, events: &Sender<Event>, cancel: &AtomicBool) -> Result<(), String> {
+    try!(stream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS))).map_err(|e| e.to_string()));
+
+    let mut received = offset;
+    let mut buf = [0u8; CHUNK_SIZE];
+
+    loop {
+        if size.map(|size| received >= size).unwrap_or(false) {
+            return Ok(());
+        }
+        if cancel.load(Ordering::SeqCst) {
+            return Err("cancelled".to_string());
+        }
+
+        let n = match stream.read(&mut buf) {
+            Ok(0)  => {
+                return match size {
+                    Some(size) if received < size => Err(format!("the connection was closed after {} of {} bytes", received, size)),
+                    _                             => Ok(())
+                };
+            },
+            Ok(n)  => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.to_string())
+        };
+
+        try!(file.write_all(&buf[..n]).map_err(|e| e.to_string()));
+        received += n as u64;
+
+        /*
+         * Acknowledge everything we have so far, as a 32-bit big-endian number.
+         */
+        let ack = received as u32;
+        let ack = [(ack >> 24) as u8, (ack >> 16) as u8, (ack >> 8) as u8, ack as u8];
+        try!(stream.write_all(&ack).map_err(|e| e.to_string()));
+
+        let _ = events.send(Event::Progress(id, received));
+    }
+}
+
+/*
+ * Wait for the other side of our file offer to connect, and then send `file` to it,
+ * starting at `offset` (which a RESUME may change until then).
+ */
+pub fn start_send(listener: TcpListener, file: File, size: u64, offset: Arc<Mutex<u64>>, id: usize, events: Sender<Event>, cancel: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let result = send_loop(listener, file, size, &offset, id, &events, &cancel);
+        let _ = events.send(Event::Closed(id, result));
+    });
+}
+
+fn send_loop(listener: TcpListener, mut file: File, size: u64, offset: &Mutex<u64>, id: usize, events: &Sender<Event>, cancel: &AtomicBool) -> Result<(), String> {
+    let mut stream = try!(accept(&listener, cancel));
+    let _ = events.send(Event::Connected(id, None));
+
+    let mut sent = *offset.lock().unwrap();
+    try!(file.seek(SeekFrom::Start(sent)).map_err(|e| e.to_string()));
+
+    /*
+     * The acknowledgements have to be read as they arrive, or the receiver could
+     * block on sending them while we block on sending the file.
+     */
+    let (ack_tx, acks) = channel();
+    let ack_stream = try!(stream.try_clone().map_err(|e| e.to_string()));
+    thread::spawn(move || read_acks(ack_stream, ack_tx));
+
+    let mut buf = [0u8; CHUNK_SIZE];
+    while sent < size {
+        if cancel.load(Ordering::SeqCst) {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err("cancelled".to_string());
+        }
+        let n = try!(file.read(&mut buf).map_err(|e| e.to_string()));
+        if n == 0 {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(format!("the file ended after {} of {} bytes", sent, size));
+        }
+        try!(stream.write_all(&buf[..n]).map_err(|e| e.to_string()));
+        sent += n as u64;
+        let _ = events.send(Event::Progress(id, sent));
+    }
+
+    /*
+     * Don't close the connection before the receiver has everything.
+     */
+    let deadline = Instant::now() + Duration::from_secs(ACK_TIMEOUT_S);
+    loop {
+        let now = Instant::now();
+        if now >= deadline { break }
+        match acks.recv_timeout(deadline - now) {
+            Ok(ack) if ack == size as u32       => break,
+            Ok(_)                               => { },
+            Err(RecvTimeoutError::Timeout)      => break,
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+    return Ok(());
+}
+
+fn read_acks(mut stream: TcpStream, acks: Sender<u32>) {
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n)          => pending.extend_from_slice(&buf[..n])
+        }
+        while pending.len() >= 4 {
+            let ack = pending.drain(..4).fold(0u32, |ack, b| (ack << 8) | b as u32);
+            if acks.send(ack).is_err() { return }
+        }
+    }
+}
+
+fn accept(listener: &TcpListener, cancel: &AtomicBool) -> Result<TcpStream, String> {
+    try!(listener.set_nonblocking(true).map_err(|e| e.to_string()));
+    let deadline = Instant::now() + Duration::from_secs(OFFER_TIMEOUT_S);
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                try!(stream.set_nonblocking(false).map_err(|e| e.to_string()));
+                return Ok(stream);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { },
+            Err(e)          => return Err(e.to_string())
+        }
+        if cancel.load(Ordering::SeqCst) {
+            return Err("cancelled".to_string());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("nobody connected within {} seconds", OFFER_TIMEOUT_S));
+        }
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn loopback(port: u16) -> SocketAddr {
+        return SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    }
+
+    fn temp_path(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("ircnvim-dcc-test-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        return dir.to_str().unwrap().to_string();
+    }
+
+    fn read_file(path: &str) -> Vec<u8> {
+        let mut contents = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+        return contents;
+    }
+
+    /*
+     * Wait until both ends of a transfer report that they're done.
+     */
+    fn wait_closed(events: &Receiver<Event>) -> Vec<(usize, Result<(), String>)> {
+        let mut closed = Vec::new();
+        while closed.len() < 2 {
+            match events.recv_timeout(Duration::from_secs(10)).expect("event") {
+                Event::Closed(id, result) => closed.push((id, result)),
+                _                         => { }
+            }
+        }
+        return closed;
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("CHAT chat 2130706433 5000"), Some(Request::Chat(loopback(5000))));
+        assert_eq!(parse("SEND log.txt 2130706433 5000 1234"), Some(Request::Send {
+            file: "log.txt".to_string(),
+            addr: loopback(5000),
+            size: Some(1234)
+        }));
+        assert_eq!(parse("SEND \"my log.txt\" ::1 5000"), Some(Request::Send {
+            file: "my log.txt".to_string(),
+            addr: "[::1]:5000".parse().unwrap(),
+            size: None
+        }));
+        assert_eq!(parse("RESUME log.txt 5000 100"), Some(Request::Resume {
+            file: "log.txt".to_string(),
+            port: 5000,
+            position: 100
+        }));
+        assert_eq!(parse("ACCEPT file.ext 5000 100"), Some(Request::Accept {
+            file: "file.ext".to_string(),
+            port: 5000,
+            position: 100
+        }));
+        assert_eq!(parse("SEND log.txt 2130706433 0 1234 token"), None);
+        assert_eq!(parse("SEND"), None);
+        assert_eq!(parse("CHAT chat"), None);
+    }
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(sanitize_file_name("../../.bashrc"), "bashrc");
+        assert_eq!(sanitize_file_name("C:\\logs\\today.log"), "today.log");
+        assert_eq!(sanitize_file_name(".."), "download");
+        assert_eq!(quote_file_name("my log.txt"), "\"my log.txt\"");
+        assert_eq!(encode_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))), "2130706433");
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut dcc = Dcc::new();
+        let offered = dcc.add(Transfer::new("alice", Kind::Receive, State::Offered));
+        let waiting = dcc.add(Transfer::new("alice", Kind::Send, State::Waiting));
+        assert_eq!(dcc.offered(), 1);
+
+        assert!(dcc.expire(Instant::now()).is_empty());
+        let expired = dcc.expire(Instant::now() + Duration::from_secs(OFFER_TIMEOUT_S));
+        assert_eq!(expired.iter().map(|t| t.id).collect::<Vec<_>>(), vec![offered]);
+        assert_eq!(dcc.transfers().iter().map(|t| t.id).collect::<Vec<_>>(), vec![waiting]);
+        assert_eq!(dcc.offered(), 0);
+    }
+
+    #[test]
+    fn test_existing_files() {
+        let dir = temp_path("existing");
+        let path = format!("{}/log.txt", dir);
+        assert_eq!(unique_path(&path), path);
+
+        File::create(&path).unwrap().write_all(b"first").unwrap();
+        File::create(&format!("{}.1", path)).unwrap();
+        assert_eq!(unique_path(&path), format!("{}.2", path));
+
+        assert!(open_download(&path, 0).is_err());
+        assert_eq!(read_file(&path), b"first");
+        open_download(&path, 3).unwrap();
+        assert_eq!(read_file(&path), b"fir");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_send_and_resume() {
+        let dir = temp_path("send");
+        let source = format!("{}/source", dir);
+        let contents = (0..100000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        File::create(&source).unwrap().write_all(&contents).unwrap();
+
+        let (events_tx, events) = channel();
+        let size = contents.len() as u64;
+
+        /*
+         * A whole file.
+         */
+        let listener = TcpListener::bind(loopback(0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let offset = Arc::new(Mutex::new(0));
+        start_send(listener, File::open(&source).unwrap(), size, offset, 1, events_tx.clone(), Arc::new(AtomicBool::new(false)));
+        let target = format!("{}/target", dir);
+        start_receive(addr, File::create(&target).unwrap(), 0, Some(size), 2, events_tx.clone(), Arc::new(AtomicBool::new(false)));
+
+        for (_, result) in wait_closed(&events) {
+            assert_eq!(result, Ok(()));
+        }
+        assert_eq!(read_file(&target), contents);
+
+        /*
+         * The rest of a file we already have the beginning of.
+         */
+        let resumed = format!("{}/resumed", dir);
+        File::create(&resumed).unwrap().write_all(&contents[..30000]).unwrap();
+        let listener = TcpListener::bind(loopback(0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let offset = Arc::new(Mutex::new(30000));
+        start_send(listener, File::open(&source).unwrap(), size, offset, 3, events_tx.clone(), Arc::new(AtomicBool::new(false)));
+        let file = OpenOptions::new().append(true).open(&resumed).unwrap();
+        start_receive(addr, file, 30000, Some(size), 4, events_tx.clone(), Arc::new(AtomicBool::new(false)));
+
+        for (_, result) in wait_closed(&events) {
+            assert_eq!(result, Ok(()));
+        }
+        assert_eq!(read_file(&resumed), contents);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_chat() {
+        let (events_tx, events) = channel();
+        let listener = TcpListener::bind(loopback(0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        start_chat_offer(listener, 1, events_tx.clone(), Arc::new(AtomicBool::new(false)));
+        start_chat(addr, 2, events_tx.clone());
+
+        let mut writers = Vec::new();
+        while writers.len() < 2 {
+            match events.recv_timeout(Duration::from_secs(10)).expect("event") {
+                Event::Connected(id, Some(writer)) => writers.push((id, writer)),
+                _                                  => panic!("unexpected event")
+            }
+        }
+        writers.sort_by_key(|&(id, _)| id);
+
+        write_line(&mut writers[1].1, "hello there").unwrap();
+        match events.recv_timeout(Duration::from_secs(10)).expect("event") {
+            Event::Line(1, line) => assert_eq!(line, "hello there"),
+            _                    => panic!("expected a line from the chat")
+        }
+
+        let _ = writers[0].1.shutdown(Shutdown::Both);
+        for (_, result) in wait_closed(&events) {
+            assert!(result.is_ok());
+        }
+    }
+}
@@ -0,0 +1,84 @@
+/*
+ * Readable versions of the error replies a server sends when it refuses to do
+ * something. {0} and {1} stand for the parameters following our nick, and {r} for
+ * the server's own explanation.
+ */
+static ERRORS: &'static [(u16, &'static str)] = &[
+    (401, "No such nick or channel: {0}"),
+    (402, "No such server: {0}"),
+    (403, "No such channel: {0}"),
+    (404, "Cannot send to {0}: {r}"),
+    (405, "Cannot join {0}: you have joined too many channels"),
+    (407, "Too many targets for {0}"),
+    (411, "No recipient given"),
+    (412, "No text to send"),
+    (441, "{0} is not in {1}"),
+    (442, "You are not in {0}"),
+    (443, "{0} is already in {1}"),
+    (461, "Not enough parameters for {0}"),
+    (467, "The key for {0} is already set"),
+    (471, "Cannot join {0}: the channel is full"),
+    (472, "Unknown mode: {0}"),
+    (473, "Cannot join {0}: the channel is invite only"),
+    (474, "Cannot join {0}: you are banned"),
+    (475, "Cannot join {0}: the key is wrong or missing"),
+    (476, "Invalid channel name: {0}"),
+    (477, "Cannot join {0}: {r}"),
+    (478, "Cannot add {1} to the list of {0}: the list is full"),
+    (481, "Permission denied: you are not an IRC operator"),
+    (482, "You are not a channel operator in {0}"),
+    (489, "Cannot join {0}: {r}"),
+    (501, "Unknown user mode"),
+    (502, "You cannot change the modes of other users")
+];
+
+/*
+ * The warning to show for an error reply, given its parameters (without our nick).
+ * Errors we don't know about are shown as they are.
+ */
+pub fn describe(numeric: u16, params: &[&str]) -> String {
+    let text = match ERRORS.iter().find(|&&(n, _)| n == numeric) {
+        Some(&(_, text)) => text,
+        None             => return format!("Error {}: {}", numeric, params.join(" "))
+    };
+
+    let param = |i: usize| if i + 1 < params.len() { params[i] } else { "" };
+    let reason = params.last().map(|r| *r).unwrap_or("");
+
+    return text.replace("{0}", param(0)).replace("{1}", param(1)).replace("{r}", reason);
+}
+
+/*
+ * Whether the error means that the server wouldn't let us join a channel.
+ */
+pub fn refuses_join(numeric: u16) -> bool {
+    return match numeric {
+        405 | 471 | 473 | 474 | 475 | 477 | 489 => true,
+        _                                       => false
+    };
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(401, &["foo", "No such nick/channel"]), "No such nick or channel: foo");
+        assert_eq!(describe(404, &["#rust", "Cannot send to channel (+m)"]), "Cannot send to #rust: Cannot send to channel (+m)");
+        assert_eq!(describe(441, &["foo", "#rust", "They aren't on that channel"]), "foo is not in #rust");
+        assert_eq!(describe(474, &["#rust", "Cannot join channel (+b)"]), "Cannot join #rust: you are banned");
+        assert_eq!(describe(412, &["No text to send"]), "No text to send");
+        assert_eq!(describe(499, &["#rust", "Something else"]), "Error 499: #rust Something else");
+    }
+
+    #[test]
+    fn test_missing_params() {
+        /*
+         * The trailing parameter is the reason, never the subject.
+         */
+        assert_eq!(describe(403, &["No such channel"]), "No such channel: ");
+        assert_eq!(describe(441, &["foo", "They aren't on that channel"]), "foo is not in ");
+    }
+}
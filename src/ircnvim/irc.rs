@@ -1,5 +1,6 @@
 use ircnvim::text::Text;
 use ircnvim::user::User;
+use std::collections::HashMap;
 use std::io::Write;
 use std::io;
 use std::iter::Peekable;
@@ -10,9 +11,21 @@ use self::IrcMessagePrefix::*;
 
 #[derive(Debug)]
 pub enum IrcMessageType {
+    Authenticate,
+    Cap,
+    ChannelCreated,
+    ChannelForward,
+    ChannelModeIs,
     ChannelURL,
     Error,
+    ErrorReply(u16),
+    ErroneousNick,
+    IsOn,
+    ISupport,
+    Invite,
     Join,
+    Kick,
+    LoggedIn,
     LUserClient,
     LUserOp,
     LUserUnknown,
@@ -22,18 +35,36 @@ pub enum IrcMessageType {
     MOTD,
     MOTDEnd,
     MOTDStart,
+    MonOffline,
+    MonOnline,
+    NoMOTD,
     Names,
     NamesEnd,
     Nick,
+    NickCollision,
+    NickInUse,
+    NickLocked,
+    NoTopic,
     Notice,
     Part,
     Ping,
+    Pong,
     PrivMsg,
     Quit,
+    SaslSuccess,
+    SaslFail,
+    SaslTooLong,
+    SaslAborted,
+    SaslAlready,
+    SaslMechs,
     Topic,
+    TopicChange,
     TopicWhoTime,
+    NoSuchNick,
     UnknownCommand,
+    VisibleHost,
     Welcome,
+    Whois(u16),
     YourHost,
     Created,
     MyInfo,
@@ -50,6 +81,7 @@ pub enum IrcMessagePrefix {
 pub struct IrcMessage {
     pub kind: IrcMessageType,
     pub prefix: Option<IrcMessagePrefix>,
+    tags: HashMap<String, String>,
     params: Vec<Text>,
     pub raw: Option<String>
 }
@@ -77,7 +109,61 @@ fn skip_while_ref<T, I, F>(iter: &mut Peekable<I>, f: F)
           I: Iterator<Item=T>,
           T: Copy {
 
-    loop { if !iter.peek().map(&f).unwrap_or(false) { break } }
+    loop {
+        if !iter.peek().map(&f).unwrap_or(false) { break }
+        iter.next();
+    }
+}
+
+/*
+ * Undo the escaping applied to IRCv3 tag values.
+ *
+ * \: -> ;    \s -> ' '    \\ -> \    \r -> CR    \n -> LF
+ *
+ * Any other escaped character stands for itself, and a trailing lone
+ * backslash is dropped.
+ */
+fn unescape_tag_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => result.push(';'),
+            Some('s') => result.push(' '),
+            Some('r') => result.push('\r'),
+            Some('n') => result.push('\n'),
+            Some(c)   => result.push(c),
+            None      => { }
+        }
+    }
+    return result;
+}
+
+/*
+ * Parse the tag section of a message (without the leading '@') into a map.
+ *
+ * Tags without a value are stored with an empty value, since the spec
+ * treats the two as equivalent. If a key appears more than once, the
+ * last value wins.
+ */
+fn parse_tags(tags: &str) -> Result<HashMap<String, String>, String> {
+    let mut result = HashMap::new();
+    for tag in tags.split(';') {
+        if tag.is_empty() { continue }
+        let (key, value) = match tag.find('=') {
+            Some(i) => (&tag[..i], unescape_tag_value(&tag[i+1..])),
+            None    => (tag, String::new())
+        };
+        if key.is_empty() || key == "+" {
+            return Err(format!("invalid message tag: {}", tag));
+        }
+        result.insert(key.to_string(), value);
+    }
+    return Ok(result);
 }
 
 impl IrcMessage {
@@ -92,6 +178,21 @@ impl IrcMessage {
 
         let mut bytes = bytes.iter().cloned().peekable();
 
+        let mut tags = HashMap::new();
+
+        if bytes.peek() == Some(&b'@') {
+            bytes.next();
+            let tag_bytes = take_while_ref(&mut bytes, |&c| c != b' ');
+            tags = match str::from_utf8(&tag_bytes[..]) {
+                Ok(s) => try!(parse_tags(s)),
+                _     => return Err(format!("message tags are not valid UTF-8"))
+            };
+            if bytes.peek() == None {
+                return Err(format!("unexpected end of input after message tags"));
+            }
+            skip_while_ref(&mut bytes, |&c| c == b' ');
+        }
+
         let mut prefix: Option<IrcMessagePrefix> = None;
 
         let first: String;
@@ -144,12 +245,19 @@ impl IrcMessage {
         }
 
         let kind = match &kind_string[..] {
+            "CAP"     => IrcMessageType::Cap,
+            "324"     => IrcMessageType::ChannelModeIs,
             "328"     => IrcMessageType::ChannelURL,
+            "329"     => IrcMessageType::ChannelCreated,
             "NOTICE"  => IrcMessageType::Notice,
             "PRIVMSG" => IrcMessageType::PrivMsg,
             "ERROR"   => IrcMessageType::Error,
             "NICK"    => IrcMessageType::Nick,
             "JOIN"    => IrcMessageType::Join,
+            "KICK"    => IrcMessageType::Kick,
+            "INVITE"  => IrcMessageType::Invite,
+            "TOPIC"   => IrcMessageType::TopicChange,
+            "331"     => IrcMessageType::NoTopic,
             "QUIT"    => IrcMessageType::Quit,
             "PART"    => IrcMessageType::Part,
             "251"     => IrcMessageType::LUserClient,
@@ -161,17 +269,44 @@ impl IrcMessage {
             "372"     => IrcMessageType::MOTD,
             "375"     => IrcMessageType::MOTDStart,
             "376"     => IrcMessageType::MOTDEnd,
+            "422"     => IrcMessageType::NoMOTD,
+            "900"     => IrcMessageType::LoggedIn,
+            "902"     => IrcMessageType::NickLocked,
+            "903"     => IrcMessageType::SaslSuccess,
+            "904"     => IrcMessageType::SaslFail,
+            "905"     => IrcMessageType::SaslTooLong,
+            "906"     => IrcMessageType::SaslAborted,
+            "907"     => IrcMessageType::SaslAlready,
+            "908"     => IrcMessageType::SaslMechs,
+            "AUTHENTICATE" => IrcMessageType::Authenticate,
             "001"     => IrcMessageType::Welcome,
             "002"     => IrcMessageType::YourHost,
             "003"     => IrcMessageType::Created,
             "004"     => IrcMessageType::MyInfo,
+            "005"     => IrcMessageType::ISupport,
+            "303"     => IrcMessageType::IsOn,
+            "432"     => IrcMessageType::ErroneousNick,
+            "433"     => IrcMessageType::NickInUse,
+            "436"     => IrcMessageType::NickCollision,
+            "730"     => IrcMessageType::MonOnline,
+            "731"     => IrcMessageType::MonOffline,
+            "396"     => IrcMessageType::VisibleHost,
+            "401"     => IrcMessageType::NoSuchNick,
             "421"     => IrcMessageType::UnknownCommand,
+            "276" | "301" | "307" | "311" | "312" | "313" | "314" | "317" | "318" | "319" |
+            "320" | "330" | "338" | "369" | "378" | "379" | "406" | "671"
+                      => IrcMessageType::Whois(kind_string.parse().unwrap()),
             "353"     => IrcMessageType::Names,
             "366"     => IrcMessageType::NamesEnd,
             "PING"    => IrcMessageType::Ping,
+            "PONG"    => IrcMessageType::Pong,
             "332"     => IrcMessageType::Topic,
             "333"     => IrcMessageType::TopicWhoTime,
-            _         => IrcMessageType::NotImplemented,
+            "470"     => IrcMessageType::ChannelForward,
+            _         => match kind_string.parse::<u16>() {
+                Ok(n) if n >= 400 && n < 600 => IrcMessageType::ErrorReply(n),
+                _                            => IrcMessageType::NotImplemented
+            },
         };
 
         let mut params = Vec::new();
@@ -200,6 +335,7 @@ impl IrcMessage {
         return Ok(IrcMessage {
             kind: kind,
             prefix: prefix,
+            tags: tags,
             params: params,
             raw: raw
         });
@@ -216,6 +352,18 @@ impl IrcMessage {
         };
     }
 
+    /*
+     * Returns the (unescaped) value of the tag `key`, if the message carries it.
+     * Tags that were sent without a value have an empty value.
+     */
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        return self.tags.get(key).map(|s| &s[..]);
+    }
+
+    pub fn tags(&self) -> &HashMap<String, String> {
+        return &self.tags;
+    }
+
     pub fn param(&self, i: usize) -> &Text {
         return &self.params[i];
     }
@@ -299,6 +447,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pong() {
+        let message = IrcMessage::parse(b":irc.example.com PONG irc.example.com :1453221453000").unwrap();
+        match message.kind {
+            IrcMessageType::Pong => { },
+            _                    => unreachable!()
+        }
+        assert_eq!(message.params().last().unwrap().text(), "1453221453000");
+    }
+
     #[test]
     fn test_quit() {
         let message = IrcMessage::parse(b":harukomoto!~harukomot@93-34-148-177.ip50.fastwebnet.it QUIT :").unwrap();
@@ -323,4 +481,50 @@ mod tests {
 
         assert_eq!(message.params().len(), 3);
     }
+
+    #[test]
+    fn test_tags() {
+        let message = IrcMessage::parse(b"@time=2016-01-01T12:00:00.000Z;msgid=abc;+example.com/typing :nick!user@host PRIVMSG #chan :hi").unwrap();
+        match message.kind {
+            IrcMessageType::PrivMsg => { },
+            _                       => unreachable!()
+        }
+        assert_eq!(message.source(), "nick");
+        assert_eq!(message.tag("time"), Some("2016-01-01T12:00:00.000Z"));
+        assert_eq!(message.tag("msgid"), Some("abc"));
+        assert_eq!(message.tag("+example.com/typing"), Some(""));
+        assert_eq!(message.tag("account"), None);
+        assert_eq!(message.params().len(), 2);
+    }
+
+    #[test]
+    fn test_tags_without_prefix() {
+        let message = IrcMessage::parse(b"@batch=xyz PING :irc.example.com").unwrap();
+        match message.kind {
+            IrcMessageType::Ping => { },
+            _                    => unreachable!()
+        }
+        assert!(message.prefix.is_none());
+        assert_eq!(message.tag("batch"), Some("xyz"));
+    }
+
+    #[test]
+    fn test_tag_escapes() {
+        let message = IrcMessage::parse(b"@a=one\\:two\\sthree\\\\four\\r\\n;b=x\\y;c=trailing\\;a2= PING :x").unwrap();
+        assert_eq!(message.tag("a"), Some("one;two three\\four\r\n"));
+        assert_eq!(message.tag("b"), Some("xy"));
+        assert_eq!(message.tag("c"), Some("trailing"));
+        assert_eq!(message.tag("a2"), Some(""));
+    }
+
+    #[test]
+    fn test_tags_duplicate_key() {
+        let message = IrcMessage::parse(b"@k=1;k=2 PING :x").unwrap();
+        assert_eq!(message.tag("k"), Some("2"));
+    }
+
+    #[test]
+    fn test_tags_only() {
+        assert!(IrcMessage::parse(b"@k=1").is_err());
+    }
 }
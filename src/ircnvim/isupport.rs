@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::str;
+
+/*
+ * How the server decides whether two nicks or channel names are the same.
+ */
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CaseMapping {
+    Ascii,         // A-Z are the same as a-z
+    Rfc1459,       // also []\~ are the same as {}|^
+    StrictRfc1459  // also []\ are the same as {}|
+}
+
+impl CaseMapping {
+    /*
+     * Unknown casemappings (e.g. rfc7613) are treated as ascii, which they extend.
+     */
+    pub fn from_name(name: &str) -> CaseMapping {
+        return match &name.to_lowercase()[..] {
+            "rfc1459"        => CaseMapping::Rfc1459,
+            "strict-rfc1459" => CaseMapping::StrictRfc1459,
+            _                => CaseMapping::Ascii
+        };
+    }
+
+    /*
+     * Fold `s` to lower case, so that names which are the same fold to the same string.
+     */
+    pub fn fold(&self, s: &str) -> String {
+        return s.chars().map(|c| match (c, *self) {
+            (_, _) if c >= 'A' && c <= 'Z'       => ((c as u8) + 32) as char,
+            ('[', CaseMapping::Rfc1459)
+            | ('[', CaseMapping::StrictRfc1459)  => '{',
+            (']', CaseMapping::Rfc1459)
+            | (']', CaseMapping::StrictRfc1459)  => '}',
+            ('\\', CaseMapping::Rfc1459)
+            | ('\\', CaseMapping::StrictRfc1459) => '|',
+            ('~', CaseMapping::Rfc1459)          => '^',
+            _                                    => c
+        }).collect();
+    }
+
+    pub fn equals(&self, a: &str, b: &str) -> bool {
+        return a.len() == b.len() && self.fold(a) == self.fold(b);
+    }
+}
+
+/*
+ * What the server has told us about itself in RPL_ISUPPORT (005) replies, e.g.
+ *
+ * :irc.example.com 005 marchelzo CHANTYPES=# PREFIX=(qaohv)~&@%+ NICKLEN=30 :are supported by this server
+ *
+ * Until the server says otherwise, the defaults from RFC 1459 apply.
+ */
+pub struct ServerInfo {
+    pub chantypes: String,
+    pub prefixes: Vec<(char, char)>, // (mode, prefix), from the highest rank to the lowest
+    pub casemapping: CaseMapping,
+    pub chanmodes: Vec<String>,      // the A, B, C and D lists of CHANMODES
+    pub modes: Option<usize>,        // how many modes with a parameter fit in one MODE; None if unlimited
+    pub nicklen: Option<usize>,
+    pub topiclen: Option<usize>,
+    pub network: Option<String>,
+    pub monitor: bool,
+    targmax: HashMap<String, Option<usize>>
+}
+
+impl ServerInfo {
+    pub fn new() -> ServerInfo {
+        return ServerInfo {
+            chantypes: "#&".to_string(),
+            prefixes: vec![('o', '@'), ('v', '+')],
+            casemapping: CaseMapping::Rfc1459,
+            chanmodes: vec!["b".to_string(), "k".to_string(), "l".to_string(), "imnpst".to_string()],
+            modes: Some(3),
+            nicklen: None,
+            topiclen: None,
+            network: None,
+            monitor: false,
+            targmax: HashMap::new()
+        };
+    }
+
+    /*
+     * Handle one token of a 005 reply: KEY, KEY=value, or -KEY to go back to the default.
+     */
+    pub fn add_token(&mut self, token: &str) {
+        if token.starts_with('-') {
+            self.reset(&token[1..]);
+            return;
+        }
+
+        let (key, value) = match token.find('=') {
+            Some(i) => (&token[..i], Some(unescape(&token[i+1..]))),
+            None    => (token, None)
+        };
+
+        let number = value.as_ref().and_then(|v| v.parse::<usize>().ok());
+
+        match key {
+            "CHANTYPES"   => self.chantypes = value.clone().unwrap_or(String::new()),
+            "PREFIX"      => self.prefixes = parse_prefix(value.as_ref().map(|s| &s[..]).unwrap_or("")),
+            "CASEMAPPING" => self.casemapping = CaseMapping::from_name(value.as_ref().map(|s| &s[..]).unwrap_or("rfc1459")),
+            "CHANMODES"   => {
+                let mut lists = value.as_ref().map(|s| &s[..]).unwrap_or("").split(',').map(|s| s.to_string()).collect::<Vec<_>>();
+                lists.resize(4, String::new());
+                self.chanmodes = lists;
+            },
+            "MODES"       => self.modes = number,
+            "NICKLEN"     => self.nicklen = number,
+            "TOPICLEN"    => self.topiclen = number,
+            "NETWORK"     => self.network = value.clone(),
+            "MONITOR"     => self.monitor = true,
+            "TARGMAX"     => {
+                self.targmax.clear();
+                for limit in value.as_ref().map(|s| &s[..]).unwrap_or("").split(',') {
+                    if let Some(i) = limit.find(':') {
+                        self.targmax.insert(limit[..i].to_uppercase(), limit[i+1..].parse::<usize>().ok());
+                    }
+                }
+            },
+            _             => { }
+        }
+    }
+
+    fn reset(&mut self, key: &str) {
+        let defaults = ServerInfo::new();
+        match key {
+            "CHANTYPES"   => self.chantypes = defaults.chantypes,
+            "PREFIX"      => self.prefixes = defaults.prefixes,
+            "CASEMAPPING" => self.casemapping = defaults.casemapping,
+            "CHANMODES"   => self.chanmodes = defaults.chanmodes,
+            "MODES"       => self.modes = defaults.modes,
+            "NICKLEN"     => self.nicklen = None,
+            "TOPICLEN"    => self.topiclen = None,
+            "NETWORK"     => self.network = None,
+            "MONITOR"     => self.monitor = false,
+            "TARGMAX"     => self.targmax.clear(),
+            _             => { }
+        }
+    }
+
+    pub fn is_channel_name(&self, name: &str) -> bool {
+        return name.chars().next().map(|c| self.chantypes.contains(c)).unwrap_or(false);
+    }
+
+    /*
+     * Split the membership prefixes (e.g. "@+") off the front of an entry in a NAMES reply.
+     */
+    pub fn split_prefixes<'a>(&self, entry: &'a str) -> (&'a str, &'a str) {
+        let i = entry.find(|c| !self.prefixes.iter().any(|&(_, p)| p == c)).unwrap_or(entry.len());
+        return (&entry[..i], &entry[i..]);
+    }
+
+    pub fn is_prefix_mode(&self, mode: char) -> bool {
+        return self.prefixes.iter().any(|&(m, _)| m == mode);
+    }
+
+    /*
+     * The modes for the prefixes in `prefixes`, e.g. "@+" -> ['o', 'v'].
+     */
+    pub fn prefix_modes(&self, prefixes: &str) -> Vec<char> {
+        return prefixes.chars().filter_map(|p| self.prefixes.iter().find(|&&(_, q)| p == q).map(|&(m, _)| m)).collect();
+    }
+
+    /*
+     * The prefix of the highest ranked mode in `modes`, e.g. ['v', 'o'] -> '@'.
+     */
+    pub fn highest_prefix(&self, modes: &[char]) -> Option<char> {
+        return self.prefixes.iter().find(|&&(m, _)| modes.contains(&m)).map(|&(_, p)| p);
+    }
+
+    /*
+     * The most targets `command` accepts at once, or None if there's no limit.
+     */
+    pub fn max_targets(&self, command: &str) -> Option<usize> {
+        return self.targmax.get(&command.to_uppercase()).and_then(|n| *n);
+    }
+}
+
+/*
+ * e.g., "(qaohv)~&@%+" -> [(q, ~), (a, &), (o, @), (h, %), (v, +)]
+ */
+fn parse_prefix(value: &str) -> Vec<(char, char)> {
+    if !value.starts_with('(') {
+        return Vec::new();
+    }
+    return match value.find(')') {
+        Some(i) => value[1..i].chars().zip(value[i+1..].chars()).collect(),
+        None    => Vec::new()
+    };
+}
+
+/*
+ * Values may contain \xHH escapes, e.g. NETWORK=Example\x20Network.
+ */
+fn unescape(value: &str) -> String {
+    let mut result = Vec::new();
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i+1] == b'x' {
+            if let Some(b) = str::from_utf8(&bytes[i+2..i+4]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                result.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    return String::from_utf8_lossy(&result).into_owned();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn info(tokens: &[&str]) -> ServerInfo {
+        let mut info = ServerInfo::new();
+        for token in tokens {
+            info.add_token(token);
+        }
+        return info;
+    }
+
+    #[test]
+    fn test_defaults() {
+        let info = ServerInfo::new();
+        assert!(info.is_channel_name("#rust"));
+        assert!(info.is_channel_name("&local"));
+        assert!(!info.is_channel_name("marchelzo"));
+        assert!(!info.is_channel_name(""));
+        assert_eq!(info.split_prefixes("@+marchelzo"), ("@+", "marchelzo"));
+        assert_eq!(info.modes, Some(3));
+        assert_eq!(info.nicklen, None);
+    }
+
+    #[test]
+    fn test_tokens() {
+        let info = info(&[
+            "CHANTYPES=#!", "PREFIX=(qaohv)~&@%+", "CASEMAPPING=ascii", "NICKLEN=30", "TOPICLEN=390",
+            "CHANMODES=beI,k,l,imnpstrS", "MODES=4", "NETWORK=Example\\x20Net", "MONITOR=100",
+            "TARGMAX=NAMES:1,PRIVMSG:4,JOIN:", "EXCEPTS"
+        ]);
+        assert!(info.is_channel_name("!chan"));
+        assert!(!info.is_channel_name("&chan"));
+        assert_eq!(info.prefixes, vec![('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')]);
+        assert_eq!(info.split_prefixes("~%nick"), ("~%", "nick"));
+        assert_eq!(info.split_prefixes("&nick"), ("&", "nick"));
+        assert_eq!(info.prefix_modes("@%+"), vec!['o', 'h', 'v']);
+        assert_eq!(info.highest_prefix(&['v', 'h']), Some('%'));
+        assert_eq!(info.highest_prefix(&[]), None);
+        assert!(info.is_prefix_mode('q'));
+        assert!(!info.is_prefix_mode('b'));
+        assert_eq!(info.casemapping, CaseMapping::Ascii);
+        assert_eq!(info.nicklen, Some(30));
+        assert_eq!(info.topiclen, Some(390));
+        assert_eq!(info.chanmodes, vec!["beI", "k", "l", "imnpstrS"]);
+        assert_eq!(info.modes, Some(4));
+        assert_eq!(info.network, Some("Example Net".to_string()));
+        assert!(info.monitor);
+        assert_eq!(info.max_targets("privmsg"), Some(4));
+        assert_eq!(info.max_targets("JOIN"), None);
+    }
+
+    #[test]
+    fn test_casemapping() {
+        assert_eq!(CaseMapping::Ascii.fold("Foo[Bar]~"), "foo[bar]~");
+        assert_eq!(CaseMapping::Rfc1459.fold("Foo[Bar]\\~"), "foo{bar}|^");
+        assert_eq!(CaseMapping::StrictRfc1459.fold("Foo[Bar]\\~"), "foo{bar}|~");
+        assert!(CaseMapping::Rfc1459.equals("#Rust", "#rust"));
+        assert!(CaseMapping::Rfc1459.equals("nick[a]", "NICK{A}"));
+        assert!(!CaseMapping::Ascii.equals("nick[a]", "nick{a}"));
+        assert!(!CaseMapping::Rfc1459.equals("ÄÖ", "äö"));
+        assert_eq!(CaseMapping::from_name("strict-rfc1459"), CaseMapping::StrictRfc1459);
+        assert_eq!(CaseMapping::from_name("rfc7613"), CaseMapping::Ascii);
+    }
+
+    #[test]
+    fn test_negation() {
+        let info = info(&["CHANTYPES=#", "NICKLEN=9", "MONITOR", "-CHANTYPES", "-NICKLEN", "-MONITOR"]);
+        assert_eq!(info.chantypes, "#&");
+        assert_eq!(info.nicklen, None);
+        assert!(!info.monitor);
+    }
+
+    #[test]
+    fn test_empty_values() {
+        let info = info(&["CHANTYPES=", "PREFIX=", "MODES"]);
+        assert!(!info.is_channel_name("#rust"));
+        assert_eq!(info.split_prefixes("@nick"), ("", "@nick"));
+        assert_eq!(info.modes, None);
+    }
+}
@@ -0,0 +1,177 @@
+use std::io::Read;
+use std::io;
+
+/*
+ * A message may carry up to 8191 bytes of tags (including the leading @ and
+ * the trailing space) on top of the usual 512 bytes.
+ */
+pub const MAX_TAGS_SIZE: usize = 8191;
+pub const MAX_MESSAGE_SIZE: usize = 512;
+
+const READ_SIZE: usize = 4096;
+
+/*
+ * Splits what is read from a connection into lines. Lines end with CRLF, or with
+ * a bare LF, which some servers send; the line ending isn't part of the line.
+ * Empty lines carry no message, and are skipped.
+ *
+ * A line longer than `max_length` is reported as an ErrorKind::InvalidData error,
+ * and the rest of it is skipped, so that reading can go on with the next line. The
+ * end of the stream is reported as ErrorKind::UnexpectedEof, since the server is
+ * not supposed to close the connection on us.
+ */
+pub struct LineReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    max_length: usize,
+    skipping: bool
+}
+
+impl<R: Read> LineReader<R> {
+    pub fn new(inner: R, max_length: usize) -> LineReader<R> {
+        return LineReader {
+            inner: inner,
+            buf: Vec::new(),
+            max_length: max_length,
+            skipping: false
+        };
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        return &mut self.inner;
+    }
+
+    pub fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        /*
+         * Only the bytes read since the last search need to be looked at.
+         */
+        let mut searched = 0;
+        loop {
+            if let Some(i) = self.buf[searched..].iter().position(|&b| b == b'\n') {
+                let end = searched + i;
+                let mut line = self.buf.drain(..end + 1).collect::<Vec<_>>();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+
+                searched = 0;
+                if self.skipping {
+                    self.skipping = false;
+                    continue;
+                }
+                if line.is_empty() {
+                    continue;
+                }
+                if line.len() > self.max_length {
+                    return Err(too_long(self.max_length));
+                }
+                return Ok(line);
+            }
+
+            /*
+             * Don't let a line without an end fill up memory; drop what we have of it,
+             * and everything up to the next line ending.
+             */
+            if self.buf.len() > self.max_length + 1 {
+                self.buf.clear();
+                searched = 0;
+                if !self.skipping {
+                    self.skipping = true;
+                    return Err(too_long(self.max_length));
+                }
+            } else {
+                searched = self.buf.len();
+            }
+
+            let mut chunk = [0u8; READ_SIZE];
+            let n = try!(self.inner.read(&mut chunk));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server"));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+fn too_long(max_length: usize) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, format!("received a line longer than {} bytes", max_length));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::{Cursor, ErrorKind, Read};
+    use std::io;
+
+    fn read_all<R: Read>(reader: &mut LineReader<R>) -> (Vec<String>, ErrorKind) {
+        let mut lines = Vec::new();
+        loop {
+            match reader.read_line() {
+                Ok(line)                                         => lines.push(String::from_utf8(line).unwrap()),
+                Err(ref e) if e.kind() == ErrorKind::InvalidData => lines.push("<too long>".to_string()),
+                Err(e)                                           => return (lines, e.kind())
+            }
+        }
+    }
+
+    /*
+     * Hands out its bytes a few at a time, like a socket would.
+     */
+    struct Trickle {
+        bytes: Vec<u8>,
+        pos: usize,
+        step: usize
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = *[self.step, buf.len(), self.bytes.len() - self.pos].iter().min().unwrap();
+            buf[..n].copy_from_slice(&self.bytes[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let mut reader = LineReader::new(Cursor::new(b"\r\nPING :a\r\nPING :b\n\r\n\nPING :c\r\nPING :d\r".to_vec()), 512);
+        let (lines, error) = read_all(&mut reader);
+        assert_eq!(lines, vec!["PING :a", "PING :b", "PING :c"]);
+        assert_eq!(error, ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_partial_reads() {
+        let bytes = b":irc.test 001 nick :Welcome\r\n:irc.test 002 nick :Your host\r\n".to_vec();
+        for step in 1..8 {
+            let mut reader = LineReader::new(Trickle { bytes: bytes.clone(), pos: 0, step: step }, 512);
+            let (lines, error) = read_all(&mut reader);
+            assert_eq!(lines, vec![":irc.test 001 nick :Welcome", ":irc.test 002 nick :Your host"]);
+            assert_eq!(error, ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn test_long_lines() {
+        /*
+         * Longer than a single read, but within the limit.
+         */
+        let long = (0..10000).map(|_| "x").collect::<String>();
+        let mut reader = LineReader::new(Cursor::new(format!("{}\r\nnext\r\n", long).into_bytes()), 10000);
+        let (lines, _) = read_all(&mut reader);
+        assert_eq!(lines, vec![long.clone(), "next".to_string()]);
+
+        /*
+         * Over the limit, whether or not its end has been read yet.
+         */
+        let mut reader = LineReader::new(Cursor::new(format!("{}\r\nnext\r\n", long).into_bytes()), 9999);
+        let (lines, _) = read_all(&mut reader);
+        assert_eq!(lines, vec!["<too long>", "next"]);
+
+        let mut reader = LineReader::new(Cursor::new(format!("a\r\n{}\r\nnext\r\n", long).into_bytes()), 100);
+        let (lines, _) = read_all(&mut reader);
+        assert_eq!(lines, vec!["a", "<too long>", "next"]);
+    }
+}
@@ -0,0 +1,139 @@
+/*
+ * The optional markup for formatting what we send, in the style of irssi:
+ *
+ * %B bold, %I italic, %U underline, %V reverse, %M monospace, %S strikethrough,
+ * %O to reset everything, %Cfg[,bg] for a color from the palette (%C alone to reset
+ * the colors), %XRRGGBB for any color, and %% for a %.
+ *
+ * e.g., "%C4red%O and %Bbold%B"
+ *
+ * Only the upper case codes count, so that text like printf("%s %d") or "50%off"
+ * goes through as it is.
+ */
+pub fn translate(input: &str) -> String {
+    let mut output = String::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if c != '%' {
+            output.push(c);
+            continue;
+        }
+
+        let code = match chars.peek() {
+            Some(&(_, code)) => code,
+            None             => {
+                output.push('%');
+                break;
+            }
+        };
+
+        let control = match code {
+            'B' => "\x02",
+            'I' => "\x1D",
+            'U' => "\x1F",
+            'V' => "\x16",
+            'M' => "\x11",
+            'S' => "\x1E",
+            'O' => "\x0F",
+            '%' => "%",
+            'C' => {
+                chars.next();
+                let (colors, n) = color(&input[i+2..]);
+                output.push_str(&colors);
+                for _ in 0..n { chars.next(); }
+                continue;
+            },
+            'X' => {
+                chars.next();
+                let (colors, n) = hex_color(&input[i+2..]);
+                output.push_str(&colors);
+                for _ in 0..n { chars.next(); }
+                continue;
+            },
+            _   => {
+                output.push('%');
+                continue;
+            }
+        };
+
+        chars.next();
+        output.push_str(control);
+    }
+
+    return output;
+}
+
+fn count_digits(s: &str, max: usize) -> usize {
+    return s.bytes().take(max).take_while(|&b| b >= b'0' && b <= b'9').count();
+}
+
+/*
+ * The colors following %C, as \x03 and two digits each, so that digits in the text
+ * that follows can't be mistaken for part of them. Returns the control codes, and
+ * how many characters of `s` they replace.
+ */
+fn color(s: &str) -> (String, usize) {
+    let n = count_digits(s, 2);
+    if n == 0 {
+        return ("\x03".to_string(), 0);
+    }
+
+    let fg = s[..n].parse::<u8>().unwrap();
+    if s[n..].starts_with(',') {
+        let m = count_digits(&s[n+1..], 2);
+        if m > 0 {
+            let bg = s[n+1..n+1+m].parse::<u8>().unwrap();
+            return (format!("\x03{:02},{:02}", fg, bg), n + 1 + m);
+        }
+    }
+
+    return (format!("\x03{:02}", fg), n);
+}
+
+fn count_hex_digits(s: &str) -> usize {
+    return s.chars().take(6).take_while(|c| c.is_digit(16)).count();
+}
+
+/*
+ * The same as color, for the RRGGBB colors following %X.
+ */
+fn hex_color(s: &str) -> (String, usize) {
+    if count_hex_digits(s) < 6 {
+        return ("\x04".to_string(), 0);
+    }
+
+    if s[6..].starts_with(',') && count_hex_digits(&s[7..]) == 6 {
+        return (format!("\x04{}", &s[..13]), 13);
+    }
+
+    return (format!("\x04{}", &s[..6]), 6);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_styles() {
+        assert_eq!(translate("plain text"), "plain text");
+        assert_eq!(translate("%Bbold%B %Iitalic%O"), "\x02bold\x02 \x1Ditalic\x0F");
+        assert_eq!(translate("printf(\"%s %d\"), 50%off %c %x"), "printf(\"%s %d\"), 50%off %c %x");
+        assert_eq!(translate("%U%V%M%S"), "\x1F\x16\x11\x1E");
+        assert_eq!(translate("100%% sure, 100% sure%"), "100% sure, 100% sure%");
+        assert_eq!(translate("%Z%é"), "%Z%é");
+    }
+
+    #[test]
+    fn test_colors() {
+        assert_eq!(translate("%C4red"), "\x0304red");
+        assert_eq!(translate("%C4,12x"), "\x0304,12x");
+        assert_eq!(translate("%C041st"), "\x03041st");
+        assert_eq!(translate("%C4,x"), "\x0304,x");
+        assert_eq!(translate("%Cplain"), "\x03plain");
+        assert_eq!(translate("%XFF8800orange"), "\x04FF8800orange");
+        assert_eq!(translate("%Xff8800,000000x"), "\x04ff8800,000000x");
+        assert_eq!(translate("%XFF88 x"), "\x04FF88 x");
+    }
+}
@@ -0,0 +1,214 @@
+use ircnvim::isupport::ServerInfo;
+
+/*
+ * The kinds of channel modes, from PREFIX and the four lists in CHANMODES.
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ModeKind {
+    Membership, // PREFIX, e.g. o: always has a nick as its parameter
+    List,       // A, e.g. b: adds to or removes from a list
+    Parameter,  // B, e.g. k: always has a parameter
+    Setting,    // C, e.g. l: has a parameter only when set
+    Flag        // D, e.g. m: never has a parameter (as do modes we don't know)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: char,
+    pub kind: ModeKind,
+    pub param: Option<String>
+}
+
+impl ModeChange {
+    /*
+     * Describe the change for the channel's room, e.g. "foo gives operator status to bar".
+     */
+    pub fn describe(&self, source: &str, channel: &str) -> String {
+        let param = self.param.as_ref().map(|s| &s[..]).unwrap_or("");
+        let sign = if self.adding { '+' } else { '-' };
+        return match (self.kind, self.mode, self.adding) {
+            (ModeKind::Membership, m, true)  => format!("{} gives {} to {}", source, rank_name(m), param),
+            (ModeKind::Membership, m, false) => format!("{} removes {} from {}", source, rank_name(m), param),
+            (ModeKind::List, 'b', true)      => format!("{} bans {}", source, param),
+            (ModeKind::List, 'b', false)     => format!("{} unbans {}", source, param),
+            (ModeKind::List, 'e', true)      => format!("{} adds a ban exception for {}", source, param),
+            (ModeKind::List, 'e', false)     => format!("{} removes the ban exception for {}", source, param),
+            (ModeKind::List, 'I', true)      => format!("{} adds an invite exception for {}", source, param),
+            (ModeKind::List, 'I', false)     => format!("{} removes the invite exception for {}", source, param),
+            (_, 'k', true)                   => format!("{} sets the channel key", source),
+            (_, 'k', false)                  => format!("{} removes the channel key", source),
+            (_, 'l', true)                   => format!("{} limits {} to {} users", source, channel, param),
+            (_, 'l', false)                  => format!("{} removes the user limit", source),
+            (ModeKind::Flag, m, adding)      => match flag_name(m) {
+                Some(name) if adding => format!("{} makes {} {} (+{})", source, channel, name, m),
+                Some(name)           => format!("{} makes {} no longer {} (-{})", source, channel, name, m),
+                None                 => format!("{} sets mode {}{} on {}", source, sign, m, channel)
+            },
+            (_, m, _) if param.is_empty()    => format!("{} sets mode {}{} on {}", source, sign, m, channel),
+            (_, m, _)                        => format!("{} sets mode {}{} {} on {}", source, sign, m, param, channel)
+        };
+    }
+}
+
+fn rank_name(mode: char) -> String {
+    return match mode {
+        'q' => "owner status".to_string(),
+        'a' => "admin status".to_string(),
+        'o' => "operator status".to_string(),
+        'h' => "half-operator status".to_string(),
+        'v' => "voice".to_string(),
+        m   => format!("mode +{}", m)
+    };
+}
+
+fn flag_name(mode: char) -> Option<&'static str> {
+    return match mode {
+        'i' => Some("invite-only"),
+        'm' => Some("moderated"),
+        'n' => Some("closed to outside messages"),
+        'p' => Some("private"),
+        's' => Some("secret"),
+        't' => Some("topic-locked"),
+        _   => None
+    };
+}
+
+/*
+ * Split a channel mode string and its parameters into individual changes, e.g.
+ *
+ * "+ov-l" ["marchelzo", "foo"] -> [+o marchelzo, +v foo, -l]
+ *
+ * Which modes take a parameter depends on the server's PREFIX and CHANMODES.
+ */
+pub fn parse(modes: &str, params: &[&str], info: &ServerInfo) -> Vec<ModeChange> {
+    let mut changes = Vec::new();
+    let mut params = params.iter();
+    let mut adding = true;
+
+    for mode in modes.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            _   => {
+                let kind = kind(mode, info);
+                let param = if takes_param(kind, adding) {
+                    params.next().map(|p| p.to_string())
+                } else {
+                    None
+                };
+                changes.push(ModeChange {
+                    adding: adding,
+                    mode: mode,
+                    kind: kind,
+                    param: param
+                });
+            }
+        }
+    }
+
+    return changes;
+}
+
+pub fn kind(mode: char, info: &ServerInfo) -> ModeKind {
+    if info.is_prefix_mode(mode) {
+        return ModeKind::Membership;
+    }
+    let in_list = |i: usize| info.chanmodes.get(i).map(|l| l.contains(mode)).unwrap_or(false);
+    return if in_list(0) {
+        ModeKind::List
+    } else if in_list(1) {
+        ModeKind::Parameter
+    } else if in_list(2) {
+        ModeKind::Setting
+    } else {
+        ModeKind::Flag
+    };
+}
+
+fn takes_param(kind: ModeKind, adding: bool) -> bool {
+    return match kind {
+        ModeKind::Membership | ModeKind::List | ModeKind::Parameter => true,
+        ModeKind::Setting                                           => adding,
+        ModeKind::Flag                                              => false
+    };
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ircnvim::isupport::ServerInfo;
+
+    fn change(adding: bool, mode: char, kind: ModeKind, param: Option<&str>) -> ModeChange {
+        return ModeChange {
+            adding: adding,
+            mode: mode,
+            kind: kind,
+            param: param.map(|p| p.to_string())
+        };
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut info = ServerInfo::new();
+        info.add_token("PREFIX=(qaohv)~&@%+");
+        info.add_token("CHANMODES=beI,k,l,imnpst");
+
+        assert_eq!(parse("+ov-l", &["marchelzo", "foo"], &info), vec![
+            change(true, 'o', ModeKind::Membership, Some("marchelzo")),
+            change(true, 'v', ModeKind::Membership, Some("foo")),
+            change(false, 'l', ModeKind::Setting, None)
+        ]);
+
+        assert_eq!(parse("+lk-b+m", &["10", "secret", "*!*@spam"], &info), vec![
+            change(true, 'l', ModeKind::Setting, Some("10")),
+            change(true, 'k', ModeKind::Parameter, Some("secret")),
+            change(false, 'b', ModeKind::List, Some("*!*@spam")),
+            change(true, 'm', ModeKind::Flag, None)
+        ]);
+
+        assert_eq!(parse("-qh+a", &["a", "b", "c"], &info), vec![
+            change(false, 'q', ModeKind::Membership, Some("a")),
+            change(false, 'h', ModeKind::Membership, Some("b")),
+            change(true, 'a', ModeKind::Membership, Some("c"))
+        ]);
+
+        assert_eq!(parse("+Zz", &["x"], &info), vec![
+            change(true, 'Z', ModeKind::Flag, None),
+            change(true, 'z', ModeKind::Flag, None)
+        ]);
+    }
+
+    #[test]
+    fn test_missing_params() {
+        let info = ServerInfo::new();
+        assert_eq!(parse("+b", &[], &info), vec![change(true, 'b', ModeKind::List, None)]);
+        assert_eq!(parse("+oo", &["x"], &info), vec![
+            change(true, 'o', ModeKind::Membership, Some("x")),
+            change(true, 'o', ModeKind::Membership, None)
+        ]);
+    }
+
+    #[test]
+    fn test_describe() {
+        let info = ServerInfo::new();
+        let describe = |modes: &str, params: &[&str]| {
+            parse(modes, params, &info).iter().map(|c| c.describe("foo", "#rust")).collect::<Vec<_>>()
+        };
+        assert_eq!(describe("+o-v", &["bar", "baz"]), vec![
+            "foo gives operator status to bar",
+            "foo removes voice from baz"
+        ]);
+        assert_eq!(describe("+bk-l", &["*!*@spam", "secret"]), vec![
+            "foo bans *!*@spam",
+            "foo sets the channel key",
+            "foo removes the user limit"
+        ]);
+        assert_eq!(describe("+l-m+X", &["10"]), vec![
+            "foo limits #rust to 10 users",
+            "foo makes #rust no longer moderated (-m)",
+            "foo sets mode +X on #rust"
+        ]);
+    }
+}
@@ -32,7 +32,7 @@ impl Message {
      */
     pub fn action(nick: &str, body: String) -> Message {
         let source = Text::decorate_nick(nick);
-        let body = Text::action(body);
+        let body = Text::from_bytes(format!("\x01ACTION {}\x01", body).into_bytes());
         return Message::new(source, body);
     }
 
@@ -0,0 +1,55 @@
+use ircnvim::isupport::CaseMapping;
+
+/*
+ * Pick the nick to try after `rejected` was refused during registration: the
+ * next one from alt_nicks, or `rejected` with an underscore appended once the
+ * list has been used up.
+ */
+pub fn alternative(primary: &str, alt_nicks: &[String], rejected: &str, casemapping: CaseMapping) -> String {
+    let mut candidates = Some(primary).into_iter().chain(alt_nicks.iter().map(|n| &n[..]));
+    if candidates.position(|n| casemapping.equals(n, rejected)).is_some() {
+        if let Some(next) = candidates.next() {
+            return next.to_string();
+        }
+    }
+    return format!("{}_", rejected);
+}
+
+/*
+ * Returns true if `nick` appears in `list`, a list of nicks (or nick!user@host
+ * masks) separated by spaces or commas, as in replies to ISON and MONITOR.
+ */
+pub fn is_listed(list: &str, nick: &str, casemapping: CaseMapping) -> bool {
+    return list
+        .split(|c| c == ' ' || c == ',')
+        .map(|n| n.split('!').next().unwrap_or(""))
+        .any(|n| casemapping.equals(n, nick));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_alternative() {
+        let alt_nicks = vec!["marchelz0".to_string(), "mrchlz".to_string()];
+        let rfc1459 = CaseMapping::Rfc1459;
+        assert_eq!(alternative("marchelzo", &alt_nicks, "marchelzo", rfc1459), "marchelz0");
+        assert_eq!(alternative("marchelzo", &alt_nicks, "Marchelz0", rfc1459), "mrchlz");
+        assert_eq!(alternative("marchelzo", &alt_nicks, "mrchlz", rfc1459), "mrchlz_");
+        assert_eq!(alternative("marchelzo", &alt_nicks, "mrchlz_", rfc1459), "mrchlz__");
+        assert_eq!(alternative("marchelzo", &[], "marchelzo", rfc1459), "marchelzo_");
+    }
+
+    #[test]
+    fn test_is_listed() {
+        let rfc1459 = CaseMapping::Rfc1459;
+        assert!(is_listed("foo marchelzo bar", "MarchelZo", rfc1459));
+        assert!(is_listed("foo!u@h,marchelzo!~m@example.com", "marchelzo", rfc1459));
+        assert!(is_listed("m{a}rch", "M[A]rch", rfc1459));
+        assert!(!is_listed("m{a}rch", "M[A]rch", CaseMapping::Ascii));
+        assert!(!is_listed("marchelzo_ foo", "marchelzo", rfc1459));
+        assert!(!is_listed("", "marchelzo", rfc1459));
+    }
+}
@@ -0,0 +1,72 @@
+/*
+ * Phrases used by the common services packages (Atheme, Anope, ...) to tell us
+ * whether IDENTIFY worked. They are matched case-insensitively.
+ */
+const SUCCESS_PHRASES: &'static [&'static str] = &[
+    "you are now identified",
+    "you are successfully identified",
+    "password accepted",
+    "you are now logged in"
+];
+
+const FAILURE_PHRASES: &'static [&'static str] = &[
+    "invalid password",
+    "incorrect password",
+    "password incorrect",
+    "is not a registered nickname",
+    "isn't registered",
+    "is not registered",
+    "authentication failed"
+];
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum NickServState {
+    Unused,        // we don't authenticate with NickServ
+    Registering,   // we'll identify as soon as registration completes
+    Waiting(u64),  // IDENTIFY was sent; we give up at the given time (precise_time_ns)
+    Identified,
+    Failed
+}
+
+impl NickServState {
+    pub fn is_pending(&self) -> bool {
+        return match *self {
+            NickServState::Registering | NickServState::Waiting(_) => true,
+            _                                                      => false
+        };
+    }
+}
+
+/*
+ * Classify a NOTICE from NickServ.
+ *
+ * success -> Some(true)
+ * failure -> Some(false)
+ * other   -> None
+ */
+pub fn classify_reply(text: &str) -> Option<bool> {
+    let text = text.to_lowercase();
+    if SUCCESS_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(true);
+    }
+    if FAILURE_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(false);
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_classify_reply() {
+        assert_eq!(classify_reply("You are now identified for \x02marchelzo\x02."), Some(true));
+        assert_eq!(classify_reply("Password accepted - you are now recognized."), Some(true));
+        assert_eq!(classify_reply("Invalid password for \x02marchelzo\x02."), Some(false));
+        assert_eq!(classify_reply("Password incorrect."), Some(false));
+        assert_eq!(classify_reply("\x02foo\x02 is not a registered nickname."), Some(false));
+        assert_eq!(classify_reply("This nickname is registered. Please choose a different nickname."), None);
+    }
+}
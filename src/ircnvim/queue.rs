@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/*
+ * Outgoing lines, held back so that we don't get killed for flooding. This is a
+ * token bucket: up to `burst` lines go out at once, after which one more may be
+ * sent every `delay`.
+ */
+pub struct SendQueue<T> {
+    lines: VecDeque<T>,
+    burst: u32,
+    delay: Duration,
+    tokens: u32,
+    refilled: Instant
+}
+
+impl<T> SendQueue<T> {
+    pub fn new(burst: u32, delay: Duration) -> SendQueue<T> {
+        return SendQueue {
+            lines: VecDeque::new(),
+            burst: burst,
+            delay: delay,
+            tokens: burst,
+            refilled: Instant::now()
+        };
+    }
+
+    pub fn push(&mut self, line: T) {
+        self.lines.push_back(line);
+    }
+
+    /*
+     * The next line that may be sent at `now`, if any.
+     */
+    pub fn pop(&mut self, now: Instant) -> Option<T> {
+        if self.lines.is_empty() { return None }
+
+        if self.delay == Duration::from_secs(0) {
+            return self.lines.pop_front();
+        }
+
+        self.refill(now);
+        if self.tokens == 0 { return None }
+
+        self.tokens -= 1;
+        return self.lines.pop_front();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.lines.len();
+    }
+
+    /*
+     * Drop everything that hasn't been sent yet, and return how many lines that was.
+     */
+    pub fn clear(&mut self) -> usize {
+        let n = self.lines.len();
+        self.lines.clear();
+        return n;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now < self.refilled { return }
+
+        while self.tokens < self.burst && now - self.refilled >= self.delay {
+            self.tokens += 1;
+            self.refilled += self.delay;
+        }
+
+        /*
+         * A full bucket doesn't keep filling, so the time spent full doesn't count.
+         */
+        if self.tokens == self.burst {
+            self.refilled = now;
+        }
+    }
+}
+
+/*
+ * Whether `line` has to go out right away rather than wait its turn: the replies
+ * that keep the connection alive, and QUIT, which we send just before exiting.
+ */
+pub fn is_urgent(line: &str) -> bool {
+    let command = line.split(' ').next().unwrap_or("");
+    return match &command.to_uppercase()[..] {
+        "PING" | "PONG" | "QUIT" => true,
+        _                        => false
+    };
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn drain(queue: &mut SendQueue<String>, now: Instant) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(line) = queue.pop(now) {
+            lines.push(line);
+        }
+        return lines;
+    }
+
+    #[test]
+    fn test_burst() {
+        let delay = Duration::from_millis(2000);
+        let mut queue = SendQueue::new(3, delay);
+        let start = Instant::now();
+        for i in 0..6 {
+            queue.push(format!("PRIVMSG #a :{}", i));
+        }
+
+        assert_eq!(drain(&mut queue, start), vec!["PRIVMSG #a :0", "PRIVMSG #a :1", "PRIVMSG #a :2"]);
+        assert_eq!(queue.len(), 3);
+
+        assert!(drain(&mut queue, start + Duration::from_millis(1999)).is_empty());
+        assert_eq!(drain(&mut queue, start + delay), vec!["PRIVMSG #a :3"]);
+        assert_eq!(drain(&mut queue, start + delay * 3), vec!["PRIVMSG #a :4", "PRIVMSG #a :5"]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_full_bucket() {
+        let delay = Duration::from_millis(1000);
+        let mut queue = SendQueue::new(2, delay);
+        let start = Instant::now();
+
+        /*
+         * Being idle for a long time doesn't earn more than `burst` lines.
+         */
+        for i in 0..4 {
+            queue.push(format!("{}", i));
+        }
+        assert_eq!(drain(&mut queue, start + delay * 10), vec!["0", "1"]);
+        assert!(drain(&mut queue, start + delay * 10).is_empty());
+        assert_eq!(drain(&mut queue, start + delay * 11), vec!["2"]);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut queue = SendQueue::new(0, Duration::from_secs(0));
+        for i in 0..100 {
+            queue.push(format!("{}", i));
+        }
+        assert_eq!(drain(&mut queue, Instant::now()).len(), 100);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut queue = SendQueue::new(1, Duration::from_secs(1));
+        queue.push("a".to_string());
+        queue.push("b".to_string());
+        assert_eq!(queue.clear(), 2);
+        assert!(queue.pop(Instant::now() + Duration::from_secs(5)).is_none());
+    }
+
+    #[test]
+    fn test_urgent() {
+        assert!(is_urgent("PONG :irc.test"));
+        assert!(is_urgent("QUIT :bye"));
+        assert!(is_urgent("quit"));
+        assert!(!is_urgent("PRIVMSG #a :PONG"));
+        assert!(!is_urgent("JOIN #a"));
+    }
+}
@@ -1,7 +1,11 @@
 use ircnvim::channel::Channel;
 use ircnvim::channel::IsChannelName;
 use ircnvim::config::Config;
+use ircnvim::isupport::CaseMapping;
+use ircnvim::isupport::ServerInfo;
+use ircnvim::mode::ModeChange;
 use ircnvim::msg::Message;
+use ircnvim::split;
 use ircnvim::text::Text;
 use ircnvim::user::User;
 use ircnvim::_my_nick_regex;
@@ -14,6 +18,7 @@ use self::NotifyLevel::*;
 pub enum RoomType {
     Channel(Channel),
     Private(String),
+    DccChat(String),
     Server
 }
 
@@ -25,43 +30,65 @@ pub enum NotifyLevel {
     Important,   // privmsg containing our nick
 }
 
+ /*
+  * Each room's messages are written to a file, one per line, which the client
+  * displays. Alongside it, the file with the same name plus .spans has a line for
+  * each of them with its formatting (see Text::spans), for the client to highlight.
+  */
  pub struct Room {
      kind: RoomType,
+     casemapping: CaseMapping,
      msgs: Vec<Message>,
      pub escaped_file_name: String,
      file: File,
+     spans_file: File,
      notify: NotifyLevel
  }
 
 impl Room {
-    pub fn new(name: &str, config: &Config) -> Room {
-        let kind = if name.is_channel_name() {
-            RoomType::Channel(Channel::new(name))
+    pub fn new(name: &str, config: &Config, info: &ServerInfo) -> Room {
+        let kind = if name.is_channel_name(info) {
+            RoomType::Channel(Channel::new(name, info.casemapping))
         } else {
             RoomType::Private(name.to_string())
         };
 
-        return Room::make(kind, config);
+        return Room::make(kind, info.casemapping, config);
     }
 
     pub fn server(config: &Config) -> Room {
-        return Room::make(RoomType::Server, config);
+        return Room::make(RoomType::Server, CaseMapping::Rfc1459, config);
     }
 
-    fn make(kind: RoomType, config: &Config) -> Room {
+    /*
+     * A DCC chat with `nick`, named =nick so that it can't be mistaken for a
+     * private conversation over IRC.
+     */
+    pub fn dcc_chat(nick: &str, config: &Config, info: &ServerInfo) -> Room {
+        return Room::make(RoomType::DccChat(format!("={}", nick)), info.casemapping, config);
+    }
+
+    fn make(kind: RoomType, casemapping: CaseMapping, config: &Config) -> Room {
         let file_name = format!("{}/{}/{}", config.directory, config.server, kind.file_name());
         let escaped_file_name = file_name.replace("#", "\\#");
 
-        let file = match File::create(file_name) {
+        let file = match File::create(&file_name) {
+            Ok(file) => file,
+            Err(e)   => panic!("{}", e)
+        };
+
+        let spans_file = match File::create(format!("{}.spans", file_name)) {
             Ok(file) => file,
             Err(e)   => panic!("{}", e)
         };
 
         return Room {
             kind: kind,
+            casemapping: casemapping,
             msgs: Vec::new(),
             escaped_file_name: escaped_file_name,
             file: file,
+            spans_file: spans_file,
             notify: Nothing,
         };
     }
@@ -80,6 +107,13 @@ impl Room {
         }
     }
 
+    pub fn is_dcc_chat(&self) -> bool {
+        match self.kind {
+            RoomType::DccChat(_) => true,
+            _                    => false
+        }
+    }
+
     pub fn is_server(&self) -> bool {
         match self.kind {
             RoomType::Server => true,
@@ -87,11 +121,16 @@ impl Room {
         }
     }
 
-    pub fn make_message(&mut self, input: &str) -> String {
+    /*
+     * The lines to send for `input`, each with the part of it that it carries. Long
+     * messages are split over several lines (see split::privmsgs).
+     */
+    pub fn make_message(&mut self, input: &str, prefix_length: usize) -> Vec<(String, String)> {
         return match self.kind {
-            RoomType::Server         => input.to_string(),
-            RoomType::Channel(ref c) => format!("PRIVMSG {} :{}", c.name, input),
-            RoomType::Private(ref n) => format!("PRIVMSG {} :{}", n, input)
+            RoomType::Server         => vec![(input.to_string(), input.to_string())],
+            RoomType::Channel(ref c) => split::privmsgs(&c.name, input, prefix_length, false),
+            RoomType::Private(ref n) => split::privmsgs(n, input, prefix_length, false),
+            RoomType::DccChat(_)     => vec![(input.to_string(), input.to_string())]
         };
     }
 
@@ -100,12 +139,39 @@ impl Room {
             RoomType::Server         => "server",
             RoomType::Channel(ref c) => &c.name,
             RoomType::Private(ref n) => &n,
+            RoomType::DccChat(ref n) => &n
+        }
+    }
+
+    /*
+     * Follow a change of the server's casemapping (see Session::update_casemapping).
+     */
+    pub fn set_casemapping(&mut self, casemapping: CaseMapping) {
+        self.casemapping = casemapping;
+        if let RoomType::Channel(ref mut c) = self.kind {
+            c.set_casemapping(casemapping);
         }
     }
 
+    /*
+     * Returns true if this is the room for `name`, according to the server's casemapping.
+     */
+    pub fn is_named(&self, name: &str) -> bool {
+        return match self.kind {
+            RoomType::Server => name == "server",
+            _                => self.casemapping.equals(self.target(), name)
+        };
+    }
+
     pub fn add_message(&mut self, message: Message) {
         let nick_regex = unsafe { &*_my_nick_regex };
         writeln!(self.file, "{}", message.text).unwrap();
+
+        /*
+         * The body is always at the end of the line.
+         */
+        let offset = message.text.len() - message.body.text().len();
+        writeln!(self.spans_file, "{}", message.body.spans(offset)).unwrap();
         if message.is_notification {
             self.notify = max(self.notify, Unimportant);
         } else if nick_regex.is_match(message.body.text()) {
@@ -149,7 +215,7 @@ impl Room {
     pub fn is_user_present(&self, user: &User) -> bool {
         return match self.kind {
             RoomType::Channel(ref c) => c.is_user_present(user),
-            RoomType::Private(ref n) => &n[..] == user.nick,
+            RoomType::Private(ref n) => self.casemapping.equals(n, &user.nick),
             _                        => false
         };
     }
@@ -161,6 +227,50 @@ impl Room {
         }
     }
 
+    /*
+     * Forget who is in the channel, e.g. when we lose the connection. The list is
+     * filled in again by NAMES once we rejoin.
+     */
+    pub fn clear_users(&mut self) {
+        if let RoomType::Channel(ref mut c) = self.kind {
+            c.clear_users();
+        }
+    }
+
+    pub fn add_member(&mut self, user: User, modes: Vec<char>) {
+        match self.kind {
+            RoomType::Channel(ref mut c) => c.add_member(user, modes),
+            _                            => unreachable!()
+        }
+    }
+
+    pub fn apply_mode(&mut self, change: &ModeChange) {
+        if let RoomType::Channel(ref mut c) = self.kind {
+            c.apply_mode(change);
+        }
+    }
+
+    pub fn clear_modes(&mut self) {
+        if let RoomType::Channel(ref mut c) = self.kind {
+            c.clear_modes();
+        }
+    }
+
+    /*
+     * The nick as it should be shown in front of a message, with the prefix of its
+     * highest rank in the channel, e.g. <@marchelzo>.
+     */
+    pub fn decorate_nick(&self, nick: &str, info: &ServerInfo) -> Text {
+        let prefix = match self.kind {
+            RoomType::Channel(ref c) => c.member(nick).and_then(|m| info.highest_prefix(&m.modes)),
+            _                        => None
+        };
+        return match prefix {
+            Some(p) => Text::decorate_nick(&format!("{}{}", p, nick)),
+            None    => Text::decorate_nick(nick)
+        };
+    }
+
     pub fn rename_user(&mut self, user: &User, new_nick: &str) {
         match self.kind {
             RoomType::Private(_)         => self.kind = RoomType::Private(new_nick.to_string()),
@@ -181,6 +291,48 @@ impl Room {
         }
     }
 
+    /*
+     * Someone was kicked from the channel. If it was us, the room stays open, but
+     * we're no longer in the channel.
+     */
+    pub fn handle_kick(&mut self, victim: &str, by: &str, reason: Option<&str>, us: bool) {
+        let target = self.target().to_string();
+        let reason = reason.map(|r| format!(" ({})", r)).unwrap_or(String::new());
+        if us {
+            self.warn(&format!("You were kicked from {} by {}{}", target, by, reason));
+        } else {
+            self.notify(&format!("{} was kicked from {} by {}{}", victim, target, by, reason));
+        }
+        match self.kind {
+            RoomType::Channel(ref mut c) => {
+                if us {
+                    c.joined = false;
+                    c.clear_users();
+                } else {
+                    c.remove_user(&User::from_nick(victim.to_string()));
+                }
+            },
+            _                            => unreachable!()
+        }
+    }
+
+    /*
+     * Whether we're (supposed to be) in the channel. We aren't after being kicked.
+     * Private and server rooms are always joined.
+     */
+    pub fn is_joined(&self) -> bool {
+        return match self.kind {
+            RoomType::Channel(ref c) => c.joined,
+            _                        => true
+        };
+    }
+
+    pub fn set_joined(&mut self, joined: bool) {
+        if let RoomType::Channel(ref mut c) = self.kind {
+            c.joined = joined;
+        }
+    }
+
     pub fn handle_part(&mut self, user: &User) {
         let notification = format!("{} [{}] has left {}", user.nick, user.to_string(), self.target());
         self.notify(&notification);
@@ -211,6 +363,40 @@ impl Room {
         }
     }
 
+    pub fn set_topic_info(&mut self, setter: &str, time: i64) {
+        match self.kind {
+            RoomType::Channel(ref mut c) => c.set_topic_info(setter, time),
+            _                            => unreachable!()
+        }
+    }
+
+    pub fn clear_topic(&mut self) {
+        match self.kind {
+            RoomType::Channel(ref mut c) => c.clear_topic(),
+            _                            => unreachable!()
+        }
+    }
+
+    /*
+     * The channel's topic, who set it, and when.
+     */
+    pub fn topic(&self) -> Option<(&str, Option<&str>, Option<i64>)> {
+        return match self.kind {
+            RoomType::Channel(ref c) => c.topic.as_ref().map(|t| (&t[..], c.topic_setter.as_ref().map(|s| &s[..]), c.topic_time)),
+            _                        => None
+        };
+    }
+
+    /*
+     * A channel's key, if we know it (from MODE or RPL_CHANNELMODEIS).
+     */
+    pub fn key(&self) -> Option<&str> {
+        return match self.kind {
+            RoomType::Channel(ref c) => c.mode('k').and_then(|k| k),
+            _                        => None
+        };
+    }
+
     pub fn goto(&self) {
         println!("GOTO {} {}", self.target(), self.escaped_file_name);
     }
@@ -241,8 +427,10 @@ impl Room {
             "[{}{}]",
             self.target(),
             match self.kind {
-                RoomType::Channel(ref c) => format!(":{}", c.num_users()),
-                _                        => "".to_string()
+                RoomType::Channel(ref c) if !c.joined                  => " (parted)".to_string(),
+                RoomType::Channel(ref c) if c.mode_string().is_empty() => format!(":{}", c.num_users()),
+                RoomType::Channel(ref c)                               => format!(":{} {}", c.num_users(), c.mode_string()),
+                _                                                      => "".to_string()
             }
         );
     }
@@ -253,7 +441,8 @@ impl RoomType {
         match *self {
             RoomType::Server         => format!("server"),
             RoomType::Channel(ref c) => format!("channel_{}", c.name),
-            RoomType::Private(ref n) => format!("private_{}", n)
+            RoomType::Private(ref n) => format!("private_{}", n),
+            RoomType::DccChat(ref n) => format!("dcc_{}", &n[1..])
         }
     }
 }
@@ -0,0 +1,447 @@
+use ircnvim::irc::IrcMessage;
+use ircnvim::irc::IrcMessageType;
+use openssl::hash::MessageDigest;
+use openssl::hash;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::base64::ToBase64;
+use rustc_serialize::base64;
+
+/*
+ * AUTHENTICATE payloads are sent in pieces of at most this many bytes.
+ */
+pub const CHUNK_SIZE: usize = 400;
+
+/*
+ * The mechanisms we try for password authentication, best first.
+ */
+const PASSWORD_MECHANISMS: &'static [Mechanism] = &[
+    Mechanism::ScramSha256,
+    Mechanism::ScramSha1,
+    Mechanism::Plain
+];
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Mechanism {
+    Plain,
+    External,
+    ScramSha256,
+    ScramSha1
+}
+
+/*
+ * What the server told us during a SASL exchange.
+ */
+#[derive(PartialEq, Eq, Debug)]
+pub enum SaslReply {
+    Challenge(String),  // AUTHENTICATE <data>; "+" means empty
+    Mechanisms(String), // 908: the mechanisms the server supports
+    Success,
+    Failure(String)
+}
+
+impl Mechanism {
+    pub fn name(&self) -> &'static str {
+        return match *self {
+            Mechanism::Plain       => "PLAIN",
+            Mechanism::External    => "EXTERNAL",
+            Mechanism::ScramSha256 => "SCRAM-SHA-256",
+            Mechanism::ScramSha1   => "SCRAM-SHA-1"
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Mechanism> {
+        return match &name.to_uppercase()[..] {
+            "PLAIN"         => Some(Mechanism::Plain),
+            "EXTERNAL"      => Some(Mechanism::External),
+            "SCRAM-SHA-256" => Some(Mechanism::ScramSha256),
+            "SCRAM-SHA-1"   => Some(Mechanism::ScramSha1),
+            _               => None
+        };
+    }
+
+    /*
+     * Whether the mechanism appears in `list`, the value of the sasl capability
+     * (e.g. "PLAIN,EXTERNAL,SCRAM-SHA-256").
+     */
+    pub fn is_in(&self, list: &str) -> bool {
+        return list.split(',').any(|m| m.eq_ignore_ascii_case(self.name()));
+    }
+
+    /*
+     * Choose a password mechanism from the ones the server advertises. Servers that
+     * don't advertise any (CAP LS without 302) are assumed to support PLAIN.
+     */
+    pub fn choose(advertised: Option<&str>) -> Mechanism {
+        return match advertised {
+            Some(list) => PASSWORD_MECHANISMS.iter().cloned().find(|m| m.is_in(list)).unwrap_or(Mechanism::Plain),
+            None       => Mechanism::Plain
+        };
+    }
+}
+
+/*
+ * The client side of a SASL exchange.
+ */
+pub struct Exchange {
+    mechanism: Mechanism,
+    username: String,
+    password: String,
+    scram: Option<Scram>,
+    step: usize
+}
+
+impl Exchange {
+    pub fn new(mechanism: Mechanism, username: &str, password: Option<&str>) -> Exchange {
+        return Exchange {
+            mechanism: mechanism,
+            username: username.to_string(),
+            password: password.unwrap_or("").to_string(),
+            scram: None,
+            step: 0
+        };
+    }
+
+    /*
+     * Compute our response to the server's (decoded) challenge.
+     */
+    pub fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, String> {
+        self.step += 1;
+        return match (self.mechanism, self.step) {
+            (Mechanism::Plain, 1)       => {
+                Ok(format!("{}\0{}\0{}", self.username, self.username, self.password).into_bytes())
+            },
+            (Mechanism::External, 1)    => {
+                Ok(Vec::new())
+            },
+            (Mechanism::ScramSha256, 1) => {
+                let scram = try!(Scram::new(MessageDigest::sha256(), &self.username));
+                let first = scram.client_first();
+                self.scram = Some(scram);
+                Ok(first.into_bytes())
+            },
+            (Mechanism::ScramSha1, 1)   => {
+                let scram = try!(Scram::new(MessageDigest::sha1(), &self.username));
+                let first = scram.client_first();
+                self.scram = Some(scram);
+                Ok(first.into_bytes())
+            },
+            (Mechanism::ScramSha256, 2) | (Mechanism::ScramSha1, 2) => {
+                let server_first = try!(utf8(challenge));
+                let password = self.password.clone();
+                self.scram.as_mut().unwrap().client_final(server_first, &password).map(|s| s.into_bytes())
+            },
+            (Mechanism::ScramSha256, 3) | (Mechanism::ScramSha1, 3) => {
+                let server_final = try!(utf8(challenge));
+                self.scram.as_mut().unwrap().verify(server_final).map(|_| Vec::new())
+            },
+            (mechanism, _)              => {
+                Err(format!("unexpected challenge during SASL {}", mechanism.name()))
+            }
+        };
+    }
+
+    /*
+     * Whether the server has done its part of the exchange. For SCRAM, this
+     * means it has proven that it knows our password.
+     */
+    pub fn is_complete(&self) -> bool {
+        return match self.scram {
+            Some(ref scram) => scram.verified,
+            None            => self.step > 0
+        };
+    }
+}
+
+/*
+ * The state of a SCRAM exchange (RFC 5802, RFC 7677).
+ */
+struct Scram {
+    digest: MessageDigest,
+    client_first_bare: String,
+    nonce: String,
+    server_signature: Option<Vec<u8>>,
+    verified: bool
+}
+
+impl Scram {
+    fn new(digest: MessageDigest, username: &str) -> Result<Scram, String> {
+        let mut nonce = [0u8; 18];
+        try!(rand_bytes(&mut nonce).map_err(|e| e.to_string()));
+        return Ok(Scram::with_nonce(digest, username, &nonce.to_base64(base64::STANDARD)));
+    }
+
+    fn with_nonce(digest: MessageDigest, username: &str, nonce: &str) -> Scram {
+        let username = username.replace("=", "=3D").replace(",", "=2C");
+        return Scram {
+            digest: digest,
+            client_first_bare: format!("n={},r={}", username, nonce),
+            nonce: nonce.to_string(),
+            server_signature: None,
+            verified: false
+        };
+    }
+
+    fn client_first(&self) -> String {
+        return format!("n,,{}", self.client_first_bare);
+    }
+
+    fn client_final(&mut self, server_first: &str, password: &str) -> Result<String, String> {
+        let nonce = try!(attribute(server_first, "r"));
+        let salt = try!(try!(attribute(server_first, "s")).from_base64().map_err(|e| e.to_string()));
+        let iterations = match try!(attribute(server_first, "i")).parse::<usize>() {
+            Ok(i) => i,
+            Err(e) => return Err(format!("invalid SCRAM iteration count: {}", e))
+        };
+
+        if !nonce.starts_with(&self.nonce[..]) || nonce.len() == self.nonce.len() {
+            return Err(format!("the server sent an invalid SCRAM nonce"));
+        }
+
+        /*
+         * "biws" is the base64 encoding of the GS2 header "n,,".
+         */
+        let client_final_bare = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", self.client_first_bare, server_first, client_final_bare);
+
+        let mut salted_password = vec![0u8; self.digest.size()];
+        try!(pbkdf2_hmac(password.as_bytes(), &salt[..], iterations, self.digest, &mut salted_password[..]).map_err(|e| e.to_string()));
+
+        let client_key = try!(hmac(self.digest, &salted_password[..], b"Client Key"));
+        let stored_key = try!(hash::hash(self.digest, &client_key[..]).map_err(|e| e.to_string()));
+        let client_signature = try!(hmac(self.digest, &stored_key[..], auth_message.as_bytes()));
+        let proof = client_key.iter().zip(client_signature.iter()).map(|(k, s)| k ^ s).collect::<Vec<u8>>();
+
+        let server_key = try!(hmac(self.digest, &salted_password[..], b"Server Key"));
+        self.server_signature = Some(try!(hmac(self.digest, &server_key[..], auth_message.as_bytes())));
+
+        return Ok(format!("{},p={}", client_final_bare, proof.to_base64(base64::STANDARD)));
+    }
+
+    fn verify(&mut self, server_final: &str) -> Result<(), String> {
+        if let Ok(error) = attribute(server_final, "e") {
+            return Err(format!("the server rejected the SCRAM exchange: {}", error));
+        }
+
+        let signature = try!(try!(attribute(server_final, "v")).from_base64().map_err(|e| e.to_string()));
+        if Some(signature) != self.server_signature {
+            return Err(format!("the server's SCRAM signature is invalid"));
+        }
+
+        self.verified = true;
+        return Ok(());
+    }
+}
+
+fn hmac(digest: MessageDigest, key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let key = try!(PKey::hmac(key).map_err(|e| e.to_string()));
+    let mut signer = try!(Signer::new(digest, &key).map_err(|e| e.to_string()));
+    try!(signer.update(data).map_err(|e| e.to_string()));
+    return signer.sign_to_vec().map_err(|e| e.to_string());
+}
+
+/*
+ * Find the value of `key` in a SCRAM message of the form "k=value,k=value,...".
+ */
+fn attribute<'a>(message: &'a str, key: &str) -> Result<&'a str, String> {
+    for attr in message.split(',') {
+        if attr.len() > key.len() && attr.starts_with(key) && attr[key.len()..].starts_with("=") {
+            return Ok(&attr[key.len() + 1..]);
+        }
+    }
+    return Err(format!("the SCRAM message is missing the {} attribute", key));
+}
+
+fn utf8(bytes: &[u8]) -> Result<&str, String> {
+    return ::std::str::from_utf8(bytes).map_err(|e| e.to_string());
+}
+
+/*
+ * Encode a response as the parameter of AUTHENTICATE.
+ */
+pub fn encode(response: &[u8]) -> String {
+    if response.is_empty() {
+        return "+".to_string();
+    }
+
+    return response.to_base64(base64::STANDARD);
+}
+
+/*
+ * Decode the (reassembled) parameter of AUTHENTICATE.
+ */
+pub fn decode(data: &str) -> Result<Vec<u8>, String> {
+    if data.is_empty() || data == "+" {
+        return Ok(Vec::new());
+    }
+
+    return data.from_base64().map_err(|e| format!("invalid base64 in SASL challenge: {}", e));
+}
+
+/*
+ * Split an encoded response into AUTHENTICATE parameters of at most CHUNK_SIZE bytes.
+ * If the last piece is exactly CHUNK_SIZE bytes long, a "+" follows it so that the
+ * server knows the response is complete.
+ */
+pub fn chunks(encoded: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = encoded;
+    while rest.len() > CHUNK_SIZE {
+        chunks.push(rest[..CHUNK_SIZE].to_string());
+        rest = &rest[CHUNK_SIZE..];
+    }
+    chunks.push(rest.to_string());
+    if rest.len() == CHUNK_SIZE {
+        chunks.push("+".to_string());
+    }
+    return chunks;
+}
+
+/*
+ * Map a message to the SASL reply it represents, if any.
+ */
+pub fn classify(message: &IrcMessage) -> Option<SaslReply> {
+    use ircnvim::irc::IrcMessageType::*;
+
+    let last = message.params().last().map(|t| t.text().to_string()).unwrap_or(String::new());
+
+    return match message.kind {
+        Authenticate  => Some(SaslReply::Challenge(last)),
+        SaslSuccess   => Some(SaslReply::Success),
+        SaslAlready   => Some(SaslReply::Success),
+        SaslMechs     => Some(SaslReply::Mechanisms(message.param(1).text().to_string())),
+        SaslFail      => Some(SaslReply::Failure(failure(&message.kind))),
+        SaslTooLong   => Some(SaslReply::Failure(failure(&message.kind))),
+        SaslAborted   => Some(SaslReply::Failure(failure(&message.kind))),
+        NickLocked    => Some(SaslReply::Failure(failure(&message.kind))),
+        _             => None
+    };
+}
+
+fn failure(kind: &IrcMessageType) -> String {
+    use ircnvim::irc::IrcMessageType::*;
+
+    return match *kind {
+        SaslFail    => "the server rejected our credentials (904)",
+        SaslTooLong => "the SASL message was too long (905)",
+        SaslAborted => "the server aborted the SASL exchange (906)",
+        NickLocked  => "the account is locked or held (902)",
+        _           => "SASL authentication failed"
+    }.to_string();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ircnvim::irc::IrcMessage;
+
+    fn reply(line: &[u8]) -> Option<SaslReply> {
+        return classify(&IrcMessage::parse(line).unwrap());
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(reply(b"AUTHENTICATE +"), Some(SaslReply::Challenge("+".to_string())));
+        assert_eq!(reply(b":irc.test 903 nick :SASL authentication successful"), Some(SaslReply::Success));
+        assert_eq!(reply(b":irc.test 908 nick PLAIN,EXTERNAL :are available SASL mechanisms"), Some(SaslReply::Mechanisms("PLAIN,EXTERNAL".to_string())));
+        match reply(b":irc.test 904 nick :SASL authentication failed") {
+            Some(SaslReply::Failure(reason)) => assert!(reason.contains("904")),
+            _                                => unreachable!()
+        }
+        match reply(b":irc.test 905 nick :SASL message too long") {
+            Some(SaslReply::Failure(reason)) => assert!(reason.contains("905")),
+            _                                => unreachable!()
+        }
+        assert_eq!(reply(b":irc.test NOTICE * :*** Looking up your hostname..."), None);
+    }
+
+    #[test]
+    fn test_plain_and_external() {
+        let mut plain = Exchange::new(Mechanism::Plain, "user", Some("pass"));
+        assert_eq!(encode(&plain.respond(b"").unwrap()), "dXNlcgB1c2VyAHBhc3M=");
+        assert!(plain.respond(b"").is_err());
+
+        let mut external = Exchange::new(Mechanism::External, "user", None);
+        assert_eq!(encode(&external.respond(b"").unwrap()), "+");
+    }
+
+    #[test]
+    fn test_choose() {
+        assert_eq!(Mechanism::choose(Some("PLAIN,EXTERNAL,SCRAM-SHA-256")), Mechanism::ScramSha256);
+        assert_eq!(Mechanism::choose(Some("SCRAM-SHA-1,PLAIN")), Mechanism::ScramSha1);
+        assert_eq!(Mechanism::choose(Some("PLAIN,EXTERNAL")), Mechanism::Plain);
+        assert_eq!(Mechanism::choose(None), Mechanism::Plain);
+        assert!(Mechanism::ScramSha256.is_in("plain,scram-sha-256"));
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks("+"), vec!["+".to_string()]);
+        assert_eq!(chunks("abc"), vec!["abc".to_string()]);
+
+        let long = (0..850).map(|_| "x").collect::<String>();
+        let pieces = chunks(&long);
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(pieces[0].len(), 400);
+        assert_eq!(pieces[2].len(), 50);
+
+        let exact = (0..800).map(|_| "x").collect::<String>();
+        let pieces = chunks(&exact);
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(pieces[2], "+");
+    }
+
+    /*
+     * The example exchange from RFC 7677.
+     */
+    #[test]
+    fn test_scram_sha_256() {
+        let mut scram = Scram::with_nonce(MessageDigest::sha256(), "user", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(scram.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(
+            scram.client_final(server_first, "pencil").unwrap(),
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+
+        assert!(scram.verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").is_ok());
+        assert!(scram.verified);
+    }
+
+    /*
+     * The example exchange from RFC 5802.
+     */
+    #[test]
+    fn test_scram_sha_1() {
+        let mut scram = Scram::with_nonce(MessageDigest::sha1(), "user", "fyko+d2lbbFgONRv9qkxdawL");
+        let server_first = "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096";
+        assert_eq!(
+            scram.client_final(server_first, "pencil").unwrap(),
+            "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts="
+        );
+        assert!(scram.verify("v=rmF9pqV8S7suAoZWja4dJRkFsKQ=").is_ok());
+    }
+
+    #[test]
+    fn test_scram_rejects_bad_server() {
+        let mut scram = Scram::with_nonce(MessageDigest::sha256(), "user", "rOprNGfwEbeRWgbNEkqO");
+        assert!(scram.client_final("r=somebodyelse,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096", "pencil").is_err());
+
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        scram.client_final(server_first, "pencil").unwrap();
+        assert!(scram.verify("v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=").is_err());
+        assert!(scram.verify("e=invalid-proof").is_err());
+        assert!(!scram.verified);
+    }
+
+    #[test]
+    fn test_scram_username_escaping() {
+        let scram = Scram::with_nonce(MessageDigest::sha256(), "a=b,c", "nonce");
+        assert_eq!(scram.client_first(), "n,,n=a=3Db=2Cc,r=nonce");
+    }
+}
@@ -1,40 +1,152 @@
+use ircnvim::cap::CapState;
+use ircnvim::cap::Caps;
 use ircnvim::channel::IsChannelName;
 use ircnvim::config::AuthMethod;
 use ircnvim::config::Config;
+use ircnvim::ctcp::RateLimit;
+use ircnvim::ctcp;
+use ircnvim::dcc::Dcc;
+use ircnvim::dcc::Event;
+use ircnvim::dcc::Kind;
+use ircnvim::dcc::Request;
+use ircnvim::dcc::State;
+use ircnvim::dcc::Transfer;
+use ircnvim::dcc;
+use ircnvim::errors;
+use ircnvim::conn::Connection;
+use ircnvim::conn::Reader;
 use ircnvim::irc::IrcMessage;
+use ircnvim::irc::IrcMessageType;
+use ircnvim::isupport::ServerInfo;
+use ircnvim::lines::LineReader;
+use ircnvim::markup;
+use openssl::rand::rand_bytes;
+use ircnvim::mode;
 use ircnvim::msg::Message;
+use ircnvim::nick;
+use ircnvim::nickserv::NickServState;
+use ircnvim::nickserv;
+use ircnvim::queue::SendQueue;
+use ircnvim::queue;
 use ircnvim::room::Room;
+use ircnvim::sasl::Exchange;
+use ircnvim::sasl::Mechanism;
+use ircnvim::sasl::SaslReply;
+use ircnvim::sasl;
+use ircnvim::split;
 use ircnvim::text::Text;
 use ircnvim::user::User;
+use ircnvim::whois::WhoisInfo;
 use ircnvim::_my_nick_regex;
 use regex::Regex;
 use regex;
-use rustc_serialize::base64::ToBase64;
-use rustc_serialize::base64;
 use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
 use std::fs;
 use std::io::BufRead;
-use std::io::Read;
 use std::io::Write;
 use std::io;
-use std::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener};
 use std::process;
-use std::ptr;
 use std::str;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use time;
 use time::Timespec;
 
-const BUFFER_SIZE: usize = 512;
 const MIN_UPDATE_INTERVAL_MS: u32 = 200;
 
+/*
+ * After losing the connection, we wait RECONNECT_MIN_DELAY_MS before the first
+ * attempt to reconnect, and double the delay after each failed attempt, up to
+ * RECONNECT_MAX_DELAY_MS. Up to half of the delay is added at random, so that
+ * many clients dropped at once don't all come back at the same moment.
+ */
+const RECONNECT_MIN_DELAY_MS: u32 = 1000;
+const RECONNECT_MAX_DELAY_MS: u32 = 300000;
+
+/*
+ * How often we ask the server (with ISON) whether our primary nick is free again,
+ * on servers that don't support MONITOR.
+ */
+const NICK_RECLAIM_INTERVAL_S: u64 = 60;
+
+/*
+ * Why logging in failed. Only a refusal (SASL failing, or not being possible) is
+ * final; after a timeout or a lost connection, trying again later may well work.
+ * The server closing the connection with an ERROR counts as losing it: that is
+ * also how servers tell us that we are reconnecting too fast.
+ */
+enum LoginError {
+    Refused(String),
+    TimedOut(String),
+    Lost(String)
+}
+
+impl LoginError {
+    fn message(&self) -> &str {
+        return match *self {
+            LoginError::Refused(ref e) | LoginError::TimedOut(ref e) | LoginError::Lost(ref e) => e
+        };
+    }
+
+    fn map<F: FnOnce(&str) -> String>(self, f: F) -> LoginError {
+        return match self {
+            LoginError::Refused(e)  => LoginError::Refused(f(&e)),
+            LoginError::TimedOut(e) => LoginError::TimedOut(f(&e)),
+            LoginError::Lost(e)     => LoginError::Lost(f(&e))
+        };
+    }
+}
+
+/*
+ * A line waiting in the send queue, and what to show for it once it has been sent.
+ */
+struct Outgoing {
+    line: String,
+    echo: Option<Echo>
+}
+
+/*
+ * One of our own messages, to be added to `room`.
+ */
+struct Echo {
+    room: String,
+    source: Text,
+    body: Text
+}
+
 pub struct Session {
-    stream: TcpStream,
+    stream: Option<Connection>,
+    reader: Option<LineReader<Reader>>,
     config: Config,
     rooms: Vec<Room>,
     active_room: usize,
     status_line: String,
+    caps: Caps,
+    info: ServerInfo,
+    nickserv: NickServState,
+    autojoined: bool,
+    autojoin_held: bool,
+    rejoin: bool,
+    registered: bool,
+    pending_invite: Option<String>,
+    our_mask: Option<(String, String)>,
+    whois: HashMap<String, (String, WhoisInfo)>,
+    ctcp_limit: RateLimit,
+    dcc_limit: RateLimit,
+    dcc: Dcc,
+    queue: SendQueue<Outgoing>,
+    primary_nick: String,
+    reclaim_nick: bool,
+    last_ison: u64,
+    last_activity: u64,
+    last_ping: u64,
+    ping_sent: Option<u64>,
+    lag: Option<u64>,
 }
 
 unsafe impl Send for Session { }
@@ -45,10 +157,8 @@ impl Session {
         /*
          * Attempt to make a connection to the IRC server.
          */
-        let stream = match TcpStream::connect(&config.server[..]) {
-            Ok(stream) => stream,
-            Err(e)     => return Err(e.to_string())
-        };
+        let (stream, reader) = try!(Connection::open(&config.host, config.port, config.tls.as_ref()));
+
         /*
          * Make sure all of the necessary directories exist.
          * Try to create them if they don't.
@@ -60,18 +170,47 @@ impl Session {
         /*
          * Create the global nick regex.
          */
-        unsafe {
-            _my_nick_regex = Box::into_raw(Box::new(Regex::new(&format!(r"([^A-Z]|\b){}([^A-Z]|\b)", regex::quote(&config.nick))).unwrap()));
-        }
+        set_nick_regex(&config.nick);
+        let primary_nick = config.nick.clone();
 
         let rooms = vec![Room::server(&config)];
+        let caps = Caps::new(config.caps.clone());
+        let nickserv = if config.auth == AuthMethod::NickServ {
+            NickServState::Registering
+        } else {
+            NickServState::Unused
+        };
+
+        let queue = SendQueue::new(config.flood_burst, Duration::from_millis(config.flood_delay));
 
         return Ok(Session {
-            stream: stream,
+            stream: Some(stream),
+            reader: Some(LineReader::new(reader, config.max_line_length)),
             config: config,
             rooms: rooms,
             active_room: 0,
             status_line: String::new(),
+            caps: caps,
+            info: ServerInfo::new(),
+            nickserv: nickserv,
+            autojoined: false,
+            autojoin_held: false,
+            rejoin: false,
+            registered: false,
+            pending_invite: None,
+            our_mask: None,
+            whois: HashMap::new(),
+            ctcp_limit: RateLimit::new(),
+            dcc_limit: RateLimit::new(),
+            dcc: Dcc::new(),
+            queue: queue,
+            primary_nick: primary_nick,
+            reclaim_nick: true,
+            last_ison: time::precise_time_ns(),
+            last_activity: time::precise_time_ns(),
+            last_ping: time::precise_time_ns(),
+            ping_sent: None,
+            lag: None,
         });
     }
 
@@ -111,6 +250,7 @@ impl Session {
             loop {
                 if let Ok(mut session) = session.try_lock() {
                     n = 0;
+                    session.tick();
                     if session.should_update() {
                         session.update();
                     }
@@ -135,19 +275,13 @@ impl Session {
         });
 
         /*
-         * Next, we identify and optionally authenticate.
+         * Next, we negotiate capabilities, identify, and optionally authenticate.
          */
-        match session.lock() {
-            Ok(mut session) => {
-                if let Some(error) = match session.config.auth {
-                    AuthMethod::NoAuth   => session.identify(),
-                    AuthMethod::NickServ => session.auth_nickserv(),
-                    AuthMethod::SASL     => session.auth_sasl(),
-                } {
-                    session.die(&format!("failed to authenticate: {}", error));
-                }
-            },
-            _           => panic!("something has gone terribly wrong")
+        let mut reader = session.lock().unwrap().reader.take().expect("reader");
+        match Session::log_in(&session, &mut reader) {
+            Ok(())                        => session.lock().unwrap().reader = Some(reader),
+            Err(LoginError::Lost(error)) => session.lock().unwrap().die(&format!("lost the connection while registering: {}", error)),
+            Err(error)                    => session.lock().unwrap().die(error.message())
         }
 
 
@@ -156,17 +290,40 @@ impl Session {
          * and handle incoming messages.
          */
         let session_clone = session.clone();
-        let mut stream = session.lock().unwrap().stream.try_clone().unwrap();
         thread::spawn(move || {
             let session = session_clone;
-            let mut buf = [0u8; BUFFER_SIZE];
-            let mut idx = 0;
-            while let Ok(bytes) = Session::read_message(&mut stream, &mut buf, &mut idx) {
-                let message = IrcMessage::parse(&bytes).expect("message parse");
-                match session.lock() {
-                    Ok(mut session) => session.handle_message(message),
-                    _               => { log!("Error taking lock"); }
-                }
+            loop {
+                /*
+                 * The reader thread takes over the reading half of the connection, along with
+                 * whatever was read past the end of registration, since it may already contain
+                 * (part of) the next message.
+                 */
+                let mut reader = session.lock().unwrap().reader.take().expect("reader");
+
+                let error = loop {
+                    let bytes = match Session::read_message(&mut reader) {
+                        Ok(bytes)                                             => bytes,
+                        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                            session.lock().unwrap().server().warn(&format!("Dropped a message: {}", e));
+                            continue;
+                        },
+                        Err(e)                                                => break e.to_string()
+                    };
+                    let message = match IrcMessage::parse(&bytes) {
+                        Ok(message) => message,
+                        Err(e)      => {
+                            session.lock().unwrap().server().warn(&format!("Dropped a message: {}", e));
+                            continue;
+                        }
+                    };
+                    match session.lock() {
+                        Ok(mut session) => session.handle_message(&message),
+                        _               => { log!("Error taking lock"); }
+                    }
+                };
+
+                session.lock().unwrap().disconnected(&error);
+                Session::reconnect(&session);
             }
         });
 
@@ -232,19 +389,50 @@ impl Session {
             return;
         }
 
+        /*
+         * What's typed in the server room is a raw command, which is sent as it is.
+         */
+        let input = &if self.active_room().is_server() { input.to_string() } else { self.markup(input) };
+
+        /*
+         * DCC chats don't go through the server.
+         */
+        if self.active_room().is_dcc_chat() {
+            if self.write_dcc_chat(input) {
+                let message = Message::new(Text::decorate_nick(&self.config.nick), Text::from_bytes(input.as_bytes().to_vec()));
+                self.active_room_mut().add_message(message);
+            }
+            return;
+        }
+
+        if self.stream.is_none() {
+            self.active_room_mut().warn("Not connected to the server; the message was not sent.");
+            return;
+        }
+
+        if !self.active_room().is_joined() {
+            let warning = format!("You are not in {}; use /join {} to join it again.", self.active_room().target(), self.active_room().target());
+            self.active_room_mut().warn(&warning);
+            return;
+        }
+
         /*
          * Prepare the message and send it to the server.
          */
-        let message = self.active_room_mut().make_message(input);
-        self.send(&message);
+        let prefix_length = self.prefix_length();
+        let messages = self.active_room_mut().make_message(input, prefix_length);
 
         /*
-         * Add the message to the active room's message list
-         * so that it becomes visible in the client.
+         * Once each line has been sent, add what it carried to the
+         * active room's message list so that it becomes visible in
+         * the client, formatted the way others will see it.
          */
-        let source = Text::decorate_nick(&self.config.nick);
-        let message = Message::new(source, Text::from_string(input.to_string()));
-        self.active_room_mut().add_message(message);
+        let room = self.active_room().target().to_string();
+        let source = self.active_room().decorate_nick(&self.config.nick, &self.info);
+        for (line, sent) in messages {
+            let echo = Echo { room: room.clone(), source: source.clone(), body: Text::from_bytes(sent.into_bytes()) };
+            self.send_echoed(&line, Some(echo));
+        }
     }
 
     fn handle_command(&mut self, command_name: &str, arg: &str) {
@@ -258,11 +446,23 @@ impl Session {
         commands! {
             me => {
                 if self.active_room().is_server() { return }
-                let arg = arg.trim();
-                let target = self.active_room().target().to_string();
-                self.send(&format!("PRIVMSG {} :\x01ACTION {}\x01", target, arg));
-                let message = Message::action(&self.config.nick, arg.to_string());
-                self.active_room_mut().add_message(message);
+                let arg = &self.markup(arg.trim());
+                if self.active_room().is_dcc_chat() {
+                    if !self.write_dcc_chat(&format!("\x01ACTION {}\x01", arg)) { return }
+                    let message = Message::action(&self.config.nick, arg.to_string());
+                    self.active_room_mut().add_message(message);
+                } else {
+                    let target = self.active_room().target().to_string();
+                    let prefix_length = self.prefix_length();
+                    for (line, sent) in split::privmsgs(&target, arg, prefix_length, true) {
+                        let echo = Echo {
+                            room: target.clone(),
+                            source: Text::decorate_nick(&self.config.nick),
+                            body: Text::from_bytes(format!("\x01ACTION {}\x01", sent).into_bytes())
+                        };
+                        self.send_echoed(&line, Some(echo));
+                    }
+                }
             },
             j | join => {
                 for channel in arg.split_whitespace() {
@@ -277,8 +477,11 @@ impl Session {
                 match arg.find(' ') {
                     Some(i) => {
                         let target = &arg[..i];
-                        let message = &arg[i+1..];
-                        self.send(&format!("PRIVMSG {} :{}", target, message)); 
+                        let message = &self.markup(&arg[i+1..]);
+                        let prefix_length = self.prefix_length();
+                        for (line, _) in split::privmsgs(target, message, prefix_length, false) {
+                            self.send(&line);
+                        }
                     },
                     None    => {
                         self.active_room_mut().warn("Invalid syntax in /msg command. Syntax is /msg <target> <message>.");
@@ -293,27 +496,69 @@ impl Session {
                 self.send(arg);
             },
             nick => {
+                if let Some(max) = self.info.nicklen {
+                    if arg.trim().chars().count() > max {
+                        let warning = format!("{} is longer than the server's limit of {} characters", arg.trim(), max);
+                        self.active_room_mut().warn(&warning);
+                        return;
+                    }
+                }
+                /*
+                 * The user picked a nick, so we stop trying to get the primary one back.
+                 */
+                if arg.trim() != self.primary_nick {
+                    self.stop_reclaim();
+                }
                 self.send(&format!("NICK {}", arg.trim()));
             },
             whois => {
-                self.send(&format!("WHOIS {}", arg.trim()));
+                self.whois(arg.trim(), false);
+            },
+            whowas => {
+                self.whois(arg.trim(), true);
+            },
+            ctcp => {
+                self.ctcp(arg.trim());
+            },
+            dcc => {
+                self.dcc_command(arg.trim());
+            },
+            clearqueue => {
+                let n = self.queue.clear();
+                self.active_room_mut().notify(&format!("Dropped {} unsent line{}", n, if n == 1 { "" } else { "s" }));
+            },
+            topic => {
+                self.topic(arg.trim());
+            },
+            accept => {
+                match self.pending_invite.take() {
+                    Some(channel) => self.send(&format!("JOIN {}", channel)),
+                    None          => self.active_room_mut().warn("There is no invitation to accept.")
+                }
             }
         };
 
         self.active_room_mut().warn(&format!("{} is not a recognized command", command_name));
     }
 
-    fn handle_message(&mut self, message: IrcMessage) -> () {
+    fn handle_message(&mut self, message: &IrcMessage) -> () {
         use ircnvim::irc::IrcMessageType::*;
+        self.last_activity = time::precise_time_ns();
         match message.kind {
+            Cap => {
+                self.handle_cap(message);
+            },
             Nick => {
                 let sender = message.user();
                 let new_nick = message.param(0).text();
-                let me = message.source() == self.config.nick;
+                let me = self.is_me(message.source());
                 if me {
-                    self.config.nick = new_nick.to_string();
-                    println!("NICK {}", regex::quote(new_nick));
+                    self.set_nick(new_nick);
                     self.active_room_mut().notify(&format!("You are now known as {}", new_nick));
+                    if self.info.monitor && self.reclaim_nick && new_nick == self.primary_nick {
+                        let unmonitor = format!("MONITOR - {}", self.primary_nick);
+                        self.send(&unmonitor);
+                    }
                 }
                 for room in &mut self.rooms {
                     if room.is_user_present(&sender) {
@@ -332,7 +577,89 @@ impl Session {
                 let nick = message.param(2).text();
                 let timestamp = message.param(3).text().parse::<i64>().unwrap();
                 let time_fmt = time::at(Timespec::new(timestamp, 0)).rfc822().to_string();
-                self.get_room(channel).unwrap().notify(&format!("The topic was last set by {} on {}", nick, time_fmt));
+                let room = self.get_room(channel).unwrap();
+                room.set_topic_info(nick, timestamp);
+                room.notify(&format!("The topic was last set by {} on {}", nick, time_fmt));
+            },
+            NoTopic => {
+                let channel = message.param(1).text();
+                if let Some(room) = self.get_room(channel) {
+                    room.clear_topic();
+                    room.notify(&format!("No topic is set for {}", channel));
+                }
+            },
+            TopicChange => {
+                /*
+                 * :nick!user@host TOPIC <channel> :<topic>
+                 */
+                let channel = message.param(0).text();
+                let topic = if message.params().len() > 1 { message.param(1).text() } else { "" };
+                let setter = message.source();
+                if let Some(room) = self.get_room(channel) {
+                    if topic.is_empty() {
+                        room.clear_topic();
+                        room.notify(&format!("{} cleared the topic of {}", setter, channel));
+                    } else {
+                        room.set_topic(topic.to_string());
+                        room.set_topic_info(setter, time::get_time().sec);
+                        room.notify(&format!("{} changed the topic of {} to: {}", setter, channel, topic));
+                    }
+                }
+            },
+            Kick => {
+                /*
+                 * :nick!user@host KICK <channel> <victim> [:<reason>]
+                 */
+                let channel = message.param(0).text().to_string();
+                let victim = message.param(1).text().to_string();
+                let reason = if message.params().len() > 2 { Some(message.param(2).text()) } else { None };
+                let us = self.is_me(&victim);
+                if let Some(room) = self.get_room(&channel) {
+                    room.handle_kick(&victim, message.source(), reason, us);
+                }
+                if us && self.config.rejoin_on_kick {
+                    let key = self.get_room(&channel).and_then(|r| r.key().map(|k| k.to_string()));
+                    self.join(&[(channel, key)]);
+                }
+            },
+            Invite => {
+                /*
+                 * :nick!user@host INVITE <target> <channel>
+                 *
+                 * With invite-notify, we also hear about other people being invited.
+                 */
+                let target = message.param(0).text();
+                let channel = message.param(1).text().to_string();
+                if self.is_me(target) {
+                    let notification = format!("{} invites you to {}; type /accept to join it", message.source(), channel);
+                    self.active_room_mut().notify(&notification);
+                    self.pending_invite = Some(channel);
+                } else if let Some(room) = self.get_room(&channel) {
+                    room.notify(&format!("{} invited {} to {}", message.source(), target, channel));
+                }
+            },
+            ChannelModeIs => {
+                /*
+                 * :server 324 <nick> <channel> <modes> [<params> ...]
+                 */
+                if message.params().len() < 3 { return }
+                let channel = message.param(1).text();
+                let params = message.params()[3..].iter().map(|t| t.text()).collect::<Vec<_>>();
+                let changes = mode::parse(message.param(2).text(), &params, &self.info);
+                if let Some(room) = self.get_room(channel) {
+                    room.clear_modes();
+                    for change in &changes {
+                        room.apply_mode(change);
+                    }
+                    room.notify(&format!("The modes of {} are {}", channel, message.sequence(2)));
+                }
+            },
+            ChannelCreated => {
+                let channel = message.param(1).text();
+                if let (Some(room), Ok(timestamp)) = (self.get_room(channel), message.param(2).text().parse::<i64>()) {
+                    let time_fmt = time::at(Timespec::new(timestamp, 0)).rfc822().to_string();
+                    room.notify(&format!("{} was created on {}", channel, time_fmt));
+                }
             },
             Topic => {
                 let channel = message.param(1).text();
@@ -341,6 +668,49 @@ impl Session {
                 room.set_topic(topic.to_string());
                 room.notify_topic();
             }
+            VisibleHost => {
+                /*
+                 * :server 396 <nick> <host> :is now your displayed host
+                 */
+                let host = message.param(1).text().to_string();
+                let username = match self.our_mask {
+                    Some((ref username, _)) => username.clone(),
+                    None                    => format!("~{}", self.config.username)
+                };
+                self.our_mask = Some((username, host));
+                self.server().notify(&message.sequence(1));
+            },
+            Whois(numeric) => {
+                self.handle_whois(numeric, message);
+            },
+            NoSuchNick => {
+                let nick = message.param(1).text().to_string();
+                let key = self.info.casemapping.fold(&nick);
+                match self.whois.get_mut(&key) {
+                    Some(&mut (_, ref mut info)) => { info.add(401, message); return },
+                    None                         => { }
+                }
+                self.handle_error_reply(401, message);
+            },
+            ErrorReply(numeric) => {
+                self.handle_error_reply(numeric, message);
+            },
+            ChannelForward => {
+                /*
+                 * :server 470 <nick> <channel> <forwarded channel> :Forwarding to another channel
+                 *
+                 * The server joins us to the other channel, so open its room now.
+                 */
+                let from = message.param(1).text().to_string();
+                let to = message.param(2).text().to_string();
+                if let Some(room) = self.get_room(&from) {
+                    room.set_joined(false);
+                }
+                if self.get_room(&to).is_none() {
+                    self.join_room(&to).set_joined(false);
+                }
+                self.get_room(&to).unwrap().warn(&format!("{} is forwarded to {}", from, to));
+            },
             NotImplemented => {
                 self.server().notify(&message.sequence(0));
             },
@@ -350,23 +720,125 @@ impl Session {
             UnknownCommand => {
                 self.active_room_mut().warn(&format!("Unknown command: {}", message.param(1).text()));
             },
-            Welcome | YourHost | Created | MOTDStart | MOTD | MOTDEnd => {
+            Welcome => {
+                self.registered = true;
+                self.last_ison = time::precise_time_ns();
+
+                /*
+                 * The nick we registered with may not be the one in the profile.
+                 */
+                let nick = message.param(0).text().to_string();
+                if nick != self.config.nick {
+                    self.set_nick(&nick);
+                }
+
+                /*
+                 * Many servers end the welcome with our full nick!user@host.
+                 */
+                if let Some(mask) = message.params().last().and_then(|t| t.text().split_whitespace().last()) {
+                    if let (Some(bang), Some(at)) = (mask.find('!'), mask.rfind('@')) {
+                        if bang < at {
+                            self.our_mask = Some((mask[bang+1..at].to_string(), mask[at+1..].to_string()));
+                        }
+                    }
+                }
+
+                self.server().notify(message.param(1).text());
+                if self.nickserv == NickServState::Registering {
+                    self.auth_nickserv();
+                }
+            },
+            YourHost | Created | MOTDStart | MOTD => {
+                self.server().notify(message.param(1).text());
+            },
+            MOTDEnd | NoMOTD => {
                 self.server().notify(message.param(1).text());
+                self.autojoin();
+                self.start_reclaim();
+            },
+            ISupport => {
+                /*
+                 * :server 005 <nick> <token> [<token> ...] :are supported by this server
+                 */
+                let n = message.params().len();
+                if n > 2 {
+                    for token in &message.params()[1..n-1] {
+                        self.info.add_token(token.text());
+                    }
+                }
+                self.update_casemapping();
+                self.server().notify(&message.sequence(0));
+            },
+            NickInUse | ErroneousNick | NickCollision => {
+                let rejected = if message.params().len() > 2 { message.param(1).text() } else { "" };
+                let reason = message.params().last().map(|t| t.text()).unwrap_or("");
+                if !self.registered {
+                    /*
+                     * Registration can't complete until we find a nick the server accepts.
+                     */
+                    let next = nick::alternative(&self.primary_nick, &self.config.alt_nicks, rejected, self.info.casemapping);
+                    self.server().warn(&format!("{}: {}; trying {} instead", rejected, reason, next));
+                    self.send(&format!("NICK {}", next));
+                } else if self.reclaim_nick && self.info.casemapping.equals(rejected, &self.primary_nick) {
+                    log!("failed to reclaim {}: {}", rejected, reason);
+                } else {
+                    self.active_room_mut().warn(&format!("{}: {}", rejected, reason));
+                }
+            },
+            MonOffline => {
+                let list = message.params().last().map(|t| t.text()).unwrap_or("");
+                if nick::is_listed(list, &self.primary_nick, self.info.casemapping) {
+                    self.reclaim();
+                }
+            },
+            IsOn => {
+                let list = if message.params().len() > 1 { message.param(1).text() } else { "" };
+                if !nick::is_listed(list, &self.primary_nick, self.info.casemapping) {
+                    self.reclaim();
+                }
+            },
+            LoggedIn => {
+                let text = message.params().last().map(|t| t.text()).unwrap_or("");
+                self.server().notify(text);
+                if let NickServState::Waiting(_) = self.nickserv {
+                    self.nickserv_finished(true, "");
+                }
             },
             Error   => {
                 self.server().notify(&message.sequence(0));
             },
             Names   => {
+                /*
+                 * With multi-prefix, every rank a member has is listed, e.g. @+nick.
+                 */
                 let channel = message.param(2).text();
+                let members = message.param(3).text().split_whitespace()
+                    .map(|entry| self.info.split_prefixes(entry))
+                    .map(|(prefixes, nick)| (nick.to_string(), self.info.prefix_modes(prefixes)))
+                    .collect::<Vec<_>>();
                 let room = self.get_room(channel).unwrap();
-                let nicks = message.param(3).text();
-                for nick in nicks.split_whitespace() {
-                    room.add_user(User::from_nick(nick.to_string()));
+                for (nick, modes) in members {
+                    room.add_member(User::from_nick(nick), modes);
                 }
             },
             Mode    => {
                 let source = message.source();
                 let target = message.param(0).text();
+
+                /*
+                 * Changes to a channel's modes are applied to it and described one by one.
+                 */
+                if self.info.is_channel_name(target) && message.params().len() > 1 && self.get_room(target).is_some() {
+                    let params = message.params()[2..].iter().map(|t| t.text()).collect::<Vec<_>>();
+                    let changes = mode::parse(message.param(1).text(), &params, &self.info);
+                    let room = self.get_room(target).unwrap();
+                    for change in changes {
+                        room.apply_mode(&change);
+                        room.notify(&change.describe(source, target));
+                    }
+                    return;
+                }
+
                 let room = if self.get_room(target).is_some() {
                     self.get_room(target).expect("get room")
                 } else {
@@ -381,6 +853,13 @@ impl Session {
                 room.notify(&notification);
             },
             Notice  => {
+                if message.param(1).ctcp {
+                    self.handle_ctcp_reply(message);
+                    return;
+                }
+                if message.source().eq_ignore_ascii_case("NickServ") {
+                    self.handle_nickserv_notice(message.param(1).text());
+                }
                 let (target, text) = message.get_notice_components();
                 if self.get_room(target).is_some() {
                     self.get_room(target).unwrap().notify(text);
@@ -389,7 +868,20 @@ impl Session {
                 }
             },
             Ping    => {
-                self.send("PONG");
+                let token = message.params().last().map(|t| t.text()).unwrap_or("");
+                self.send(&format!("PONG :{}", token));
+            },
+            Pong    => {
+                /*
+                 * The token of our own PINGs is the time at which they were sent.
+                 */
+                let token = message.params().last().and_then(|t| t.text().parse::<u64>().ok());
+                if let Some(sent) = self.ping_sent {
+                    if token == Some(sent) {
+                        self.lag = Some(time::precise_time_ns() - sent);
+                        self.ping_sent = None;
+                    }
+                }
             },
             Part    => {
                 let room_name = message.param(0).text();
@@ -413,130 +905,1205 @@ impl Session {
                  * to the list of users in the target room.
                  */
                 let room_name = message.param(0).text().to_string();
-                if message.source() == self.config.nick {
-                    self.join_room(&room_name);
+                if self.is_me(message.source()) {
+                    let user = message.user();
+                    if let (Some(username), Some(host)) = (user.username, user.host) {
+                        self.our_mask = Some((username, host));
+                    }
+
+                    /*
+                     * When we rejoin after a reconnect, the room is still there.
+                     */
+                    if self.get_room(&room_name).is_some() {
+                        let room = self.get_room(&room_name).unwrap();
+                        room.set_joined(true);
+                        room.notify(&format!("Joined {}", room_name));
+                    } else {
+                        self.join_room(&room_name);
+                    }
+                    self.send(&format!("MODE {}", room_name));
                 } else {
                     let room = self.get_room(&room_name).unwrap();
                     room.handle_join(message.user());
                 }
             },
             PrivMsg => {
-                let source = Text::decorate_nick(message.source());
-                let privmsg = Message::new(source, message.param(1).clone());
+                if message.param(1).ctcp && !message.param(1).action {
+                    self.handle_ctcp_query(message);
+                    return;
+                }
                 let target = message.param(0).text().to_string();
-                let target = if target == self.config.nick { message.source() } else { &target[..] };
+                let target = if self.is_me(&target) { message.source() } else { &target[..] };
                 let already_in_room = self.get_room(target).is_some();
                 if !already_in_room {
                     self.join_room(target);
                 }
-                self.get_room(target).unwrap().add_message(privmsg);
+                let info = &self.info;
+                let room = self.rooms.iter_mut().find(|r| r.is_named(target)).unwrap();
+                let source = room.decorate_nick(message.source(), info);
+                room.add_message(Message::new(source, message.param(1).clone()));
             },
             _                       => { }
         }
     }
 
-    fn get_room(&mut self, name: &str) -> Option<&mut Room> {
-        for room in &mut self.rooms {
-            if room.target() == name {
-                return Some(room);
+    /*
+     * Handle a CAP message: LS, ACK, NAK, NEW, DEL or LIST.
+     *
+     * :server CAP <nick> <subcommand> [*] :<capabilities>
+     */
+    fn handle_cap(&mut self, message: &IrcMessage) {
+        let n = message.params().len();
+        if n < 2 { return }
+
+        let subcommand = message.param(1).text();
+
+        /*
+         * Every line of a multi-line reply but the last has a '*' before the list.
+         */
+        let more = n > 3 && message.param(2).text() == "*";
+        let list = if n > 2 { message.param(n - 1).text() } else { "" };
+
+        match subcommand {
+            "LS"   => {
+                self.caps.add_available(list);
+                if !more && self.caps.state == CapState::Listing {
+                    self.caps.state = CapState::Requesting;
+                    self.request_caps(None);
+                }
+            },
+            "NEW"  => {
+                self.caps.add_available(list);
+                self.server().notify(&format!("The server now supports: {}", list));
+                self.request_caps(Some(list));
+            },
+            "DEL"  => {
+                self.caps.remove(list);
+                self.server().notify(&format!("The server no longer supports: {}", list));
+            },
+            "ACK"  => {
+                /*
+                 * The list may also disable capabilities (-cap), so show where that leaves us.
+                 */
+                self.caps.ack(list);
+                let enabled = self.caps.enabled().join(" ");
+                self.server().notify(&format!("Enabled capabilities: {}", enabled));
+            },
+            "NAK"  => {
+                self.caps.nak(list);
+                self.server().warn(&format!("The server refused to enable: {}", list));
+            },
+            "LIST" => {
+                self.server().notify(&format!("Enabled capabilities: {}", list));
+            },
+            _      => {
+                self.server().notify(&message.sequence(0));
             }
         }
-
-        return None;
     }
 
-    fn update_status_line(&mut self) -> bool {
-        let new_status_line = self.status_line();
-        if new_status_line != self.status_line {
-            self.status_line = new_status_line;
-            return true;
+    fn request_caps(&mut self, only: Option<&str>) {
+        for request in self.caps.requests(only) {
+            self.send(&request);
         }
-        return false;
     }
 
-    fn status_line(&self) -> String {
-        let mut status = String::new();
-        for (i, room) in self.rooms.iter().enumerate() {
-            let s = if i == self.active_room {
-                room.status_string_active()
-            } else {
-                room.status_string()
-            };
-            status.push_str(&format!(" {} ", s));
-        }
-        return status;
+    /*
+     * Returns true if the capability `cap` has been enabled for this connection.
+     */
+    pub fn has_cap(&self, cap: &str) -> bool {
+        return self.caps.is_enabled(cap);
     }
 
-    fn should_update(&self) -> bool {
-        return self.active_room().should_update();
+    fn handle_nickserv_notice(&mut self, text: &str) {
+        if let NickServState::Waiting(_) = self.nickserv {
+            match nickserv::classify_reply(text) {
+                Some(success) => self.nickserv_finished(success, text),
+                None          => { }
+            }
+        }
     }
 
     /*
-     * Let the client know that they should update the active room,
-     * and clear the updated flag.
+     * Record the outcome of identifying with NickServ, and join the autojoin
+     * channels if we were holding them back.
      */
-    fn update(&mut self) {
-        println!("UPDATE");
-        self.active_room_mut().clear_notify();
-    }
+    fn nickserv_finished(&mut self, identified: bool, reason: &str) {
+        if identified {
+            self.nickserv = NickServState::Identified;
+            let notification = format!("You are now identified with NickServ as {}", self.config.nickserv_account);
+            self.server().notify(&notification);
+        } else {
+            self.nickserv = NickServState::Failed;
+            self.server().warn(&format!("Failed to identify with NickServ: {}", reason));
+        }
 
-    fn active_room(&self) -> &Room {
-        return &self.rooms[self.active_room];
+        if self.autojoin_held {
+            self.autojoin_held = false;
+            self.autojoin();
+        }
     }
 
-    fn active_room_mut(&mut self) -> &mut Room {
-        return &mut self.rooms[self.active_room];
-    }
+    /*
+     * Join the channels listed in the profile's autojoin option, once registration
+     * has completed. With hold_autojoin, we wait until NickServ has answered.
+     */
+    fn autojoin(&mut self) {
+        if self.autojoined { return }
 
-    fn server(&mut self) -> &mut Room {
-        return &mut self.rooms[0];
-    }
+        if self.config.hold_autojoin && self.nickserv.is_pending() {
+            self.autojoin_held = true;
+            return;
+        }
 
-    fn join_room(&mut self, name: &str) -> &mut Room {
-        self.rooms.push(Room::new(name, &self.config));
-        self.active_room = self.rooms.len() - 1;
-        self.active_room().goto();
-        return &mut self.rooms[self.active_room];
+        self.autojoined = true;
+
+        /*
+         * After a reconnect, we rejoin the channels that were still open instead (unless
+         * we had been kicked from them), with their keys.
+         */
+        let channels: Vec<(String, Option<String>)> = if self.rejoin {
+            self.rooms.iter()
+                .filter(|r| r.is_channel() && r.is_joined())
+                .map(|r| (r.target().to_string(), r.key().map(|k| k.to_string())))
+                .collect()
+        } else {
+            self.config.autojoin.iter().map(|c| (c.clone(), None)).collect()
+        };
+
+        self.join(&channels);
     }
 
     /*
-     * Read bytes from a TcpStream until CRLF is encountered.
+     * Join the given channels, with their keys if they have one.
      */
-    fn read_message(stream: &mut TcpStream, buf: &mut [u8], idx: &mut usize) -> Result<Vec<u8>, String> {
-
-        loop {
-            let mut i = 0;
-            while i + 1 < *idx {
-                if buf[i] == b'\r' && buf[i+1] == b'\n' {
-                    let result = buf[..i].iter().cloned().collect::<Vec<_>>();
-                    unsafe { ptr::copy(buf[i+2..].as_ptr(), buf[..].as_mut_ptr(), BUFFER_SIZE - (i + 2)); }
-                    *idx -= i + 2;
-                    if let Ok(s) = str::from_utf8(&result[..]) {
-                        log!("RECEIVED: {}", s);
-                    }
-                    return Ok(result);
-                }
+    fn join(&mut self, channels: &[(String, Option<String>)]) {
+        /*
+         * Channels with keys have to come first, since the keys are matched up with
+         * the channels in order.
+         */
+        let mut channels = channels.to_vec();
+        channels.sort_by_key(|&(_, ref key)| key.is_none());
 
-                i += 1;
+        /*
+         * Respect the server's limit on the number of channels per JOIN, if it has one.
+         */
+        let limit = cmp::max(self.info.max_targets("JOIN").unwrap_or(channels.len()), 1);
+        for group in channels.chunks(limit) {
+            let names = group.iter().map(|&(ref c, _)| &c[..]).collect::<Vec<_>>().join(",");
+            let keys = group.iter().filter_map(|&(_, ref k)| k.as_ref().map(|k| &k[..])).collect::<Vec<_>>().join(",");
+            if keys.is_empty() {
+                self.send(&format!("JOIN {}", names));
+            } else {
+                self.send(&format!("JOIN {} {}", names, keys));
             }
+        }
+    }
 
-            match stream.read(&mut buf[*idx..]) {
-                Ok(n)  => *idx += n,
-                Err(e) => return Err(e.to_string())
-            };
+    /*
+     * Answer a CTCP query (other than an ACTION) with a NOTICE, unless we've answered
+     * too many of them lately.
+     */
+    fn handle_ctcp_query(&mut self, message: &IrcMessage) {
+        let nick = message.source().to_string();
+        let (command, args) = ctcp::parse(message.param(1).text());
 
+        if command == "DCC" {
+            self.handle_dcc_request(&nick, args);
+            return;
         }
-    }
 
-    fn die(&self, error: &str) {
-        log!("Error: {}", error);
-        process::exit(-1);
-    }
+        let reply = {
+            let version = self.config.ctcp_version.as_ref().map(|v| &v[..]);
+            let userinfo = self.config.ctcp_userinfo.as_ref().map(|u| &u[..]);
+            ctcp::answer(&command, args, version, userinfo)
+        };
 
-    fn quit(&mut self, message: &str) {
-        self.send(&format!("QUIT: {}", message));
-        println!("QUIT");
-        process::exit(0);
+        if let Some(reply) = reply {
+            if !self.ctcp_limit.allow(time::precise_time_ns()) {
+                log!("Not answering CTCP {} from {}: too many queries", command, nick);
+                return;
+            }
+            self.send(&format!("NOTICE {} :{}", nick, ctcp::encode(&command, &reply)));
+        }
+
+        self.server().notify(&format!("CTCP {} query from {}", command, nick));
+    }
+
+    /*
+     * A NOTICE carrying a CTCP message is the answer to one of our queries.
+     */
+    fn handle_ctcp_reply(&mut self, message: &IrcMessage) {
+        let nick = message.source().to_string();
+        let (command, args) = ctcp::parse(message.param(1).text());
+        let now = time::precise_time_ns();
+
+        /*
+         * The argument of our PINGs is the time at which they were sent.
+         */
+        let notification = match args.parse::<u64>() {
+            Ok(sent) if command == "PING" && sent <= now => {
+                format!("CTCP PING reply from {}: {:.3}s", nick, (now - sent) as f64 / 1000000000.0)
+            },
+            _                                            => format!("CTCP {} reply from {}: {}", command, nick, args)
+        };
+
+        self.active_room_mut().notify(&notification);
+    }
+
+    /*
+     * /ctcp <nick> <command> [arguments]
+     */
+    fn ctcp(&mut self, arg: &str) {
+        let mut words = arg.splitn(3, ' ');
+        match (words.next(), words.next()) {
+            (Some(nick), Some(command)) if !nick.is_empty() && !command.is_empty() => {
+                let command = command.to_ascii_uppercase();
+                let args = match words.next() {
+                    Some(args)                => args.to_string(),
+                    None if command == "PING" => time::precise_time_ns().to_string(),
+                    None                      => String::new()
+                };
+                self.send(&format!("PRIVMSG {} :{}", nick, ctcp::encode(&command, &args)));
+            },
+            _                                                                      => {
+                self.active_room_mut().warn("Invalid syntax in /ctcp command. Syntax is /ctcp <nick> <command> [arguments].");
+            }
+        }
+    }
+
+    /*
+     * The length of the ":nick!user@host " that the server puts in front of our messages
+     * when it relays them. Until we know our host, we assume the longest possible one.
+     */
+    fn prefix_length(&self) -> usize {
+        let (username, host) = match self.our_mask {
+            Some((ref username, ref host)) => (username.len(), host.len()),
+            None                           => (self.config.username.len() + 1, split::MAX_HOST_LENGTH)
+        };
+        return 1 + self.config.nick.len() + 1 + username + 1 + host + 1;
+    }
+
+    /*
+     * Translate the input markup into control codes, if it's enabled.
+     */
+    fn markup(&self, input: &str) -> String {
+        if self.config.input_markup {
+            return markup::translate(input);
+        } else {
+            return input.to_string();
+        }
+    }
+
+    /*
+     * A CTCP DCC query: someone offers us a chat or a file, wants to resume a file we
+     * offered them, or agrees to resume one they offered us.
+     */
+    fn handle_dcc_request(&mut self, nick: &str, args: &str) {
+        match dcc::parse(args) {
+            Some(Request::Chat(_)) | Some(Request::Send { .. }) if !self.allow_dcc_offer(nick) => { },
+            Some(Request::Chat(addr)) => {
+                let mut transfer = Transfer::new(nick, Kind::Chat, State::Offered);
+                transfer.addr = Some(addr);
+                let id = self.dcc.add(transfer);
+                let notification = format!("{} wants to chat over DCC (/dcc accept {} or /dcc reject {})", nick, id, id);
+                self.active_room_mut().notify(&notification);
+            },
+            Some(Request::Send { file, addr, size }) => {
+                let mut transfer = Transfer::new(nick, Kind::Receive, State::Offered);
+                transfer.file_name = dcc::sanitize_file_name(&file);
+                transfer.path = format!("{}/{}", self.config.dcc_directory, transfer.file_name);
+                transfer.addr = Some(addr);
+                transfer.port = addr.port();
+                transfer.size = size;
+                let file_name = transfer.file_name.clone();
+                let id = self.dcc.add(transfer);
+                let size = size.map(|s| format!(" ({} bytes)", s)).unwrap_or(String::new());
+                let notification = format!("{} offers to send you {}{} over DCC (/dcc accept {} or /dcc reject {})", nick, file_name, size, id, id);
+                self.active_room_mut().notify(&notification);
+            },
+            Some(Request::Resume { file, port, position }) => {
+                let accepted = match self.dcc.find(nick, Kind::Send, State::Waiting, port) {
+                    Some(id) => {
+                        let transfer = self.dcc.get_mut(id).unwrap();
+                        if position < transfer.size.unwrap_or(0) {
+                            *transfer.offset.lock().unwrap() = position;
+                            transfer.position = position;
+                            true
+                        } else {
+                            false
+                        }
+                    },
+                    None     => false
+                };
+                if accepted {
+                    let accept = ctcp::encode("DCC", &format!("ACCEPT {} {} {}", dcc::quote_file_name(&file), port, position));
+                    self.send(&format!("PRIVMSG {} :{}", nick, accept));
+                } else {
+                    log!("Ignoring DCC RESUME from {}: {}", nick, args);
+                }
+            },
+            Some(Request::Accept { port, position, .. }) => {
+                match self.dcc.find(nick, Kind::Receive, State::Resuming, port) {
+                    Some(id) => self.start_dcc_receive(id, position),
+                    None     => { log!("Ignoring DCC ACCEPT from {}: {}", nick, args); }
+                }
+            },
+            None => {
+                let warning = format!("Unsupported DCC request from {}: {}", nick, args);
+                self.active_room_mut().warn(&warning);
+            }
+        }
+    }
+
+    /*
+     * Whether to take note of another DCC offer, so that a flood of them can't fill up
+     * the transfer list or the screen. This has a budget of its own, so that CTCP
+     * queries can't crowd out offers, nor offers our CTCP replies.
+     */
+    fn allow_dcc_offer(&mut self, nick: &str) -> bool {
+        if self.dcc.offered() >= dcc::MAX_OFFERS || !self.dcc_limit.allow(time::precise_time_ns()) {
+            log!("Ignoring DCC offer from {}: too many offers", nick);
+            return false;
+        }
+        return true;
+    }
+
+    /*
+     * /dcc [list], /dcc send <nick> <file>, /dcc chat <nick>, /dcc accept [id],
+     * /dcc reject [id] and /dcc close <id>. Without an id, accept and reject apply to
+     * the latest offer.
+     */
+    fn dcc_command(&mut self, arg: &str) {
+        let mut words = arg.splitn(3, ' ');
+        let subcommand = words.next().unwrap_or("").to_lowercase();
+        let first = words.next().map(|w| w.trim()).and_then(|w| if w.is_empty() { None } else { Some(w) });
+        let rest = words.next().map(|w| w.trim()).and_then(|w| if w.is_empty() { None } else { Some(w) });
+        let id = first.and_then(|w| w.parse::<usize>().ok());
+
+        match (&subcommand[..], first, rest) {
+            ("", _, _) | ("list", None, None)                           => self.dcc_list(),
+            ("send", Some(nick), Some(path))                            => self.dcc_send(nick, path),
+            ("chat", Some(nick), None)                                  => self.dcc_chat(nick),
+            ("accept", _, None) if first.is_none() || id.is_some()      => self.dcc_accept(id),
+            ("reject", _, None) if first.is_none() || id.is_some()      => self.dcc_reject(id),
+            ("close", Some(_), None) if id.is_some()                    => self.dcc_close(id.unwrap()),
+            _                                                           => {
+                let warning = "Invalid syntax in /dcc command. Syntax is /dcc [list | send <nick> <file> | chat <nick> | accept [id] | reject [id] | close <id>].";
+                self.active_room_mut().warn(warning);
+            }
+        }
+    }
+
+    fn dcc_list(&mut self) {
+        let lines = self.dcc.transfers().iter().map(|t| t.describe()).collect::<Vec<_>>();
+        if lines.is_empty() {
+            self.active_room_mut().notify("There are no DCC chats or transfers.");
+        }
+        for line in lines {
+            self.active_room_mut().notify(&line);
+        }
+    }
+
+    /*
+     * The address to offer DCC connections on.
+     */
+    fn dcc_address(&self) -> Option<IpAddr> {
+        return self.config.dcc_address.or(self.stream.as_ref().and_then(|s| s.local_addr()).map(|a| a.ip()));
+    }
+
+    /*
+     * Listen for the other side of an offer we're about to make. We listen on every
+     * interface, since dcc_address may well be the address of a router in front of us.
+     */
+    fn dcc_listen(&mut self) -> Option<(IpAddr, TcpListener)> {
+        let address = match self.dcc_address() {
+            Some(address) => address,
+            None          => {
+                self.active_room_mut().warn("Not connected to the server, and no dcc_address is set; can't make a DCC offer.");
+                return None;
+            }
+        };
+        let any = match address {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0))
+        };
+        return match TcpListener::bind((any, 0)) {
+            Ok(listener) => Some((address, listener)),
+            Err(e)       => {
+                self.active_room_mut().warn(&format!("Couldn't listen for a DCC connection: {}", e));
+                None
+            }
+        };
+    }
+
+    fn dcc_send(&mut self, nick: &str, path: &str) {
+        let (file, size) = match File::open(path).and_then(|f| f.metadata().map(|m| (f, m.len()))) {
+            Ok(file) => file,
+            Err(e)   => {
+                self.active_room_mut().warn(&format!("Couldn't open {}: {}", path, e));
+                return;
+            }
+        };
+        let (address, listener) = match self.dcc_listen() {
+            Some(listening) => listening,
+            None            => return
+        };
+        let port = listener.local_addr().map(|a| a.port()).unwrap_or(0);
+
+        let mut transfer = Transfer::new(nick, Kind::Send, State::Waiting);
+        transfer.file_name = dcc::sanitize_file_name(path);
+        transfer.path = path.to_string();
+        transfer.size = Some(size);
+        transfer.port = port;
+        let (offset, cancel) = (transfer.offset.clone(), transfer.cancel.clone());
+        let file_name = transfer.file_name.clone();
+        let id = self.dcc.add(transfer);
+        dcc::start_send(listener, file, size, offset, id, self.dcc.sender(), cancel);
+
+        let offer = format!("SEND {} {} {} {}", dcc::quote_file_name(&file_name), dcc::encode_ip(address), port, size);
+        self.send(&format!("PRIVMSG {} :{}", nick, ctcp::encode("DCC", &offer)));
+        self.active_room_mut().notify(&format!("Offered {} to {} over DCC ({})", file_name, nick, id));
+    }
+
+    fn dcc_chat(&mut self, nick: &str) {
+        let (address, listener) = match self.dcc_listen() {
+            Some(listening) => listening,
+            None            => return
+        };
+        let port = listener.local_addr().map(|a| a.port()).unwrap_or(0);
+
+        let mut transfer = Transfer::new(nick, Kind::Chat, State::Waiting);
+        transfer.port = port;
+        let cancel = transfer.cancel.clone();
+        let id = self.dcc.add(transfer);
+        dcc::start_chat_offer(listener, id, self.dcc.sender(), cancel);
+
+        let offer = format!("CHAT chat {} {}", dcc::encode_ip(address), port);
+        self.send(&format!("PRIVMSG {} :{}", nick, ctcp::encode("DCC", &offer)));
+        self.active_room_mut().notify(&format!("Offered {} a DCC chat ({})", nick, id));
+    }
+
+    fn dcc_accept(&mut self, id: Option<usize>) {
+        let id = match self.dcc.find_offered(id) {
+            Some(id) => id,
+            None     => {
+                self.active_room_mut().warn("There is no DCC offer to accept.");
+                return;
+            }
+        };
+
+        let (kind, nick, addr, path, file_name, size, port) = {
+            let t = self.dcc.get_mut(id).unwrap();
+            (t.kind, t.nick.clone(), t.addr.unwrap(), t.path.clone(), t.file_name.clone(), t.size, t.port)
+        };
+
+        if kind == Kind::Chat {
+            self.dcc.get_mut(id).unwrap().state = State::Active;
+            dcc::start_chat(addr, id, self.dcc.sender());
+            return;
+        }
+
+        if let Err(e) = fs::create_dir_all(&self.config.dcc_directory) {
+            let warning = format!("Couldn't create {}: {}", self.config.dcc_directory, e);
+            self.active_room_mut().warn(&warning);
+            return;
+        }
+
+        /*
+         * If we already have the beginning of the file, ask for the rest. Anything else
+         * that is in the way (the whole file, a longer one, or one of unknown size) is
+         * kept, and the download goes next to it.
+         */
+        let existing = fs::metadata(&path).map(|m| m.len()).ok();
+        match (existing, size) {
+            (Some(existing), Some(size)) if existing > 0 && existing < size => {
+                {
+                    let transfer = self.dcc.get_mut(id).unwrap();
+                    transfer.state = State::Resuming;
+                    transfer.since = Instant::now();
+                }
+                let resume = format!("RESUME {} {} {}", dcc::quote_file_name(&file_name), port, existing);
+                self.send(&format!("PRIVMSG {} :{}", nick, ctcp::encode("DCC", &resume)));
+                self.active_room_mut().notify(&format!("Asked {} to resume {} from {} bytes", nick, file_name, existing));
+            },
+            (Some(_), _)                                                    => {
+                let unique = dcc::unique_path(&path);
+                self.active_room_mut().notify(&format!("{} already exists; saving {} as {}", path, file_name, unique));
+                self.dcc.get_mut(id).unwrap().path = unique;
+                self.start_dcc_receive(id, 0);
+            },
+            (None, _)                                                       => self.start_dcc_receive(id, 0)
+        }
+    }
+
+    fn start_dcc_receive(&mut self, id: usize, offset: u64) {
+        let events = self.dcc.sender();
+        let result = {
+            let transfer = self.dcc.get_mut(id).unwrap();
+            match dcc::open_download(&transfer.path, offset) {
+                Ok(file) => {
+                    transfer.state = State::Active;
+                    transfer.position = offset;
+                    dcc::start_receive(transfer.addr.unwrap(), file, offset, transfer.size, id, events, transfer.cancel.clone());
+                    Ok(())
+                },
+                Err(e)   => Err(format!("Couldn't save {}: {}", transfer.path, e))
+            }
+        };
+
+        if let Err(warning) = result {
+            self.dcc.remove(id);
+            self.active_room_mut().warn(&warning);
+        }
+    }
+
+    fn dcc_reject(&mut self, id: Option<usize>) {
+        let transfer = match self.dcc.find_offered(id).and_then(|id| self.dcc.remove(id)) {
+            Some(transfer) => transfer,
+            None           => {
+                self.active_room_mut().warn("There is no DCC offer to reject.");
+                return;
+            }
+        };
+
+        let offer = match transfer.kind {
+            Kind::Chat => "CHAT chat".to_string(),
+            _          => format!("SEND {}", dcc::quote_file_name(&transfer.file_name))
+        };
+        self.send(&format!("NOTICE {} :{}", transfer.nick, ctcp::encode("DCC", &format!("REJECT {}", offer))));
+        self.active_room_mut().notify(&format!("Rejected DCC offer {}", transfer.describe()));
+    }
+
+    fn dcc_close(&mut self, id: usize) {
+        /*
+         * Transfers with a thread are removed once it reports that it's done.
+         */
+        let state = match self.dcc.get_mut(id) {
+            Some(transfer) => {
+                transfer.close();
+                transfer.state
+            },
+            None           => {
+                self.active_room_mut().warn(&format!("There is no DCC chat or transfer {}.", id));
+                return;
+            }
+        };
+
+        if state == State::Offered || state == State::Resuming {
+            let transfer = self.dcc.remove(id).unwrap();
+            self.active_room_mut().notify(&format!("Closed {}", transfer.describe()));
+        }
+    }
+
+    /*
+     * Send a line to the DCC chat in the active room.
+     */
+    fn write_dcc_chat(&mut self, line: &str) -> bool {
+        let nick = self.active_room().target()[1..].to_string();
+        let result = match self.dcc.chat(&nick).and_then(|chat| chat.writer.as_mut()) {
+            Some(writer) => dcc::write_line(writer, line).map_err(|e| e.to_string()),
+            None         => Err("not connected".to_string())
+        };
+        if let Err(e) = result {
+            self.active_room_mut().warn(&format!("Couldn't send to {} over DCC: {}", nick, e));
+            return false;
+        }
+        return true;
+    }
+
+    /*
+     * The room of the DCC chat with `nick`, which is opened if necessary.
+     */
+    fn dcc_chat_room(&mut self, nick: &str) -> &mut Room {
+        let name = format!("={}", nick);
+        let i = match self.rooms.iter().position(|r| r.is_named(&name)) {
+            Some(i) => i,
+            None    => {
+                self.rooms.push(Room::dcc_chat(nick, &self.config, &self.info));
+                self.rooms.len() - 1
+            }
+        };
+        return &mut self.rooms[i];
+    }
+
+    /*
+     * Take in what the DCC threads have to report.
+     */
+    fn handle_dcc_events(&mut self) {
+        for transfer in self.dcc.expire(Instant::now()) {
+            self.active_room_mut().notify(&format!("DCC offer expired: {}", transfer.describe()));
+        }
+
+        while let Some(event) = self.dcc.poll() {
+            match event {
+                Event::Connected(id, writer) => {
+                    let (kind, nick, description) = match self.dcc.get_mut(id) {
+                        Some(transfer) => {
+                            transfer.state = State::Active;
+                            transfer.writer = writer;
+                            (transfer.kind, transfer.nick.clone(), transfer.describe())
+                        },
+                        None           => continue
+                    };
+                    if kind == Kind::Chat {
+                        self.dcc_chat_room(&nick).notify(&format!("DCC chat with {} established", nick));
+                    } else {
+                        self.active_room_mut().notify(&format!("Started DCC transfer {}", description));
+                    }
+                },
+                Event::Progress(id, position) => {
+                    if let Some(transfer) = self.dcc.get_mut(id) {
+                        transfer.position = position;
+                    }
+                },
+                Event::Line(id, line) => {
+                    let nick = match self.dcc.get_mut(id) {
+                        Some(transfer) => transfer.nick.clone(),
+                        None           => continue
+                    };
+                    let message = Message::new(Text::decorate_nick(&nick), Text::from_bytes(line.into_bytes()));
+                    self.dcc_chat_room(&nick).add_message(message);
+                },
+                Event::Closed(id, result) => {
+                    let transfer = match self.dcc.remove(id) {
+                        Some(transfer) => transfer,
+                        None           => continue
+                    };
+                    let what = match transfer.kind {
+                        Kind::Chat    => format!("DCC chat with {}", transfer.nick),
+                        Kind::Send    => format!("Sending {} to {}", transfer.file_name, transfer.nick),
+                        Kind::Receive => format!("Receiving {} from {}", transfer.file_name, transfer.nick)
+                    };
+                    let notification = match (result, transfer.kind) {
+                        (Ok(()), Kind::Chat)    => format!("{} closed", what),
+                        (Ok(()), Kind::Receive) => format!("{} finished; it was saved to {}", what, transfer.path),
+                        (Ok(()), Kind::Send)    => format!("{} finished", what),
+                        (Err(e), _)             => format!("{} failed: {}", what, e)
+                    };
+                    let name = format!("={}", transfer.nick);
+                    match self.rooms.iter().position(|r| r.is_named(&name)) {
+                        Some(i) if transfer.kind == Kind::Chat => self.rooms[i].notify(&notification),
+                        _                                      => self.active_room_mut().notify(&notification)
+                    }
+                }
+            }
+        }
+    }
+
+    /*
+     * Show an error reply in the room it concerns: the first of its parameters that
+     * names one of our rooms, or else the active room.
+     */
+    fn handle_error_reply(&mut self, numeric: u16, message: &IrcMessage) {
+        let params = message.params().iter().skip(1).map(|t| t.text()).collect::<Vec<_>>();
+        let warning = errors::describe(numeric, &params);
+
+        let subjects = &params[..params.len().saturating_sub(1)];
+        let room = subjects.iter()
+            .filter_map(|p| self.rooms.iter().position(|r| !r.is_server() && r.is_named(p)))
+            .next();
+
+        match room {
+            Some(i) => {
+                if errors::refuses_join(numeric) {
+                    self.rooms[i].set_joined(false);
+                }
+                self.rooms[i].warn(&warning);
+            },
+            None    => self.active_room_mut().warn(&warning)
+        }
+    }
+
+    /*
+     * Send a WHOIS (or WHOWAS) and remember which room asked, so that the replies
+     * can be shown there once they have all arrived.
+     */
+    fn whois(&mut self, arg: &str, was: bool) {
+        let (command, nick) = if was {
+            ("WHOWAS", arg.split_whitespace().next())
+        } else {
+            ("WHOIS", arg.split_whitespace().last())
+        };
+
+        match nick {
+            Some(nick) => {
+                let key = self.info.casemapping.fold(nick);
+                let target = self.active_room().target().to_string();
+                self.whois.insert(key, (target, WhoisInfo::new(nick, was)));
+                self.send(&format!("{} {}", command, arg));
+            },
+            None       => {
+                let warning = format!("Invalid syntax in /{} command. Syntax is /{} <nick>.", command.to_lowercase(), command.to_lowercase());
+                self.active_room_mut().warn(&warning);
+            }
+        }
+    }
+
+    /*
+     * Collect the replies to a WHOIS or WHOWAS until the end of the sequence, and then
+     * show them all at once.
+     */
+    fn handle_whois(&mut self, numeric: u16, message: &IrcMessage) {
+        if message.params().len() < 2 {
+            self.server().notify(&message.sequence(0));
+            return;
+        }
+
+        let nick = message.param(1).text().to_string();
+        let key = self.info.casemapping.fold(&nick);
+
+        /*
+         * RPL_AWAY is also the answer to a PRIVMSG sent to someone who is away.
+         */
+        if numeric == 301 && !self.whois.contains_key(&key) {
+            let notification = format!("{} is away: {}", nick, message.sequence(2));
+            let i = self.rooms.iter().position(|r| r.is_named(&nick)).unwrap_or(self.active_room);
+            self.rooms[i].notify(&notification);
+            return;
+        }
+
+        /*
+         * Replies to a WHOIS we didn't send ourselves (e.g., with /raw) go to the active room.
+         */
+        if !self.whois.contains_key(&key) {
+            let was = numeric == 314 || numeric == 369 || numeric == 406;
+            let target = self.active_room().target().to_string();
+            self.whois.insert(key.clone(), (target, WhoisInfo::new(&nick, was)));
+        }
+
+        if numeric == 318 || numeric == 369 {
+            let (target, info) = self.whois.remove(&key).unwrap();
+            let i = self.rooms.iter().position(|r| r.is_named(&target)).unwrap_or(self.active_room);
+            for line in info.render() {
+                self.rooms[i].notify(&line);
+            }
+        } else {
+            self.whois.get_mut(&key).unwrap().1.add(numeric, message);
+        }
+    }
+
+    /*
+     * /topic shows the active channel's topic, and /topic <text> changes it.
+     */
+    fn topic(&mut self, topic: &str) {
+        if !self.active_room().is_channel() {
+            self.active_room_mut().warn("/topic can only be used in a channel.");
+            return;
+        }
+
+        let channel = self.active_room().target().to_string();
+
+        if !topic.is_empty() {
+            if let Some(max) = self.info.topiclen {
+                if topic.len() > max {
+                    let warning = format!("The topic is longer than the server's limit of {} characters", max);
+                    self.active_room_mut().warn(&warning);
+                    return;
+                }
+            }
+            self.send(&format!("TOPIC {} :{}", channel, topic));
+            return;
+        }
+
+        let notification = match self.active_room().topic() {
+            Some((topic, Some(setter), Some(time))) => {
+                let time_fmt = time::at(Timespec::new(time, 0)).rfc822().to_string();
+                format!("The topic for {} is {} (set by {} on {})", channel, topic, setter, time_fmt)
+            },
+            Some((topic, _, _))                     => format!("The topic for {} is {}", channel, topic),
+            None                                    => format!("No topic is set for {}", channel)
+        };
+        self.active_room_mut().notify(&notification);
+    }
+
+    /*
+     * Called periodically by the update thread, to handle anything that is time-based.
+     */
+    fn tick(&mut self) {
+        if let NickServState::Waiting(deadline) = self.nickserv {
+            if time::precise_time_ns() > deadline {
+                let reason = format!("NickServ did not respond within {} seconds", self.config.auth_timeout);
+                self.nickserv_finished(false, &reason);
+            }
+        }
+
+        self.check_link();
+        self.check_nick();
+        self.handle_dcc_events();
+        self.flush_queue();
+    }
+
+    /*
+     * PING the server every ping_interval seconds to measure the lag, and drop the
+     * connection if we haven't heard anything from the server in ping_timeout seconds.
+     * The reader thread then notices that the connection is gone and reconnects.
+     */
+    fn check_link(&mut self) {
+        if self.stream.is_none() { return }
+
+        let now = time::precise_time_ns();
+
+        if now - self.last_activity > self.config.ping_timeout * 1000000000 {
+            let reason = format!("no reply from the server in {} seconds", self.config.ping_timeout);
+            self.disconnected(&reason);
+            return;
+        }
+
+        let interval = self.config.ping_interval * 1000000000;
+        if interval > 0 && self.ping_sent.is_none() && now - self.last_ping >= interval {
+            self.send(&format!("PING :{}", now));
+            self.ping_sent = Some(now);
+            self.last_ping = now;
+        }
+    }
+
+    /*
+     * Without MONITOR, poll with ISON to find out whether our primary nick is free.
+     */
+    fn check_nick(&mut self) {
+        if self.stream.is_none() || !self.registered || self.info.monitor { return }
+        if !self.reclaim_nick || self.config.nick == self.primary_nick { return }
+
+        let now = time::precise_time_ns();
+        if now - self.last_ison >= NICK_RECLAIM_INTERVAL_S * 1000000000 {
+            let ison = format!("ISON {}", self.primary_nick);
+            self.send(&ison);
+            self.last_ison = now;
+        }
+    }
+
+    /*
+     * If we didn't get our primary nick, ask the server to tell us when it becomes
+     * free. Without MONITOR, check_nick polls for it instead.
+     */
+    fn start_reclaim(&mut self) {
+        if self.info.monitor && self.reclaim_nick && self.config.nick != self.primary_nick {
+            let monitor = format!("MONITOR + {}", self.primary_nick);
+            self.send(&monitor);
+        }
+    }
+
+    /*
+     * Our primary nick is free: try to take it back.
+     */
+    fn reclaim(&mut self) {
+        if self.reclaim_nick && self.config.nick != self.primary_nick {
+            let nick = format!("NICK {}", self.primary_nick);
+            self.send(&nick);
+        }
+    }
+
+    fn stop_reclaim(&mut self) {
+        if self.info.monitor && self.reclaim_nick && self.config.nick != self.primary_nick {
+            let unmonitor = format!("MONITOR - {}", self.primary_nick);
+            self.send(&unmonitor);
+        }
+        self.reclaim_nick = false;
+    }
+
+    /*
+     * Update our nick, along with the highlight regex and the client's syntax rules.
+     */
+    fn set_nick(&mut self, nick: &str) {
+        self.config.nick = nick.to_string();
+        set_nick_regex(nick);
+        println!("NICK {}", regex::quote(nick));
+    }
+
+    fn is_me(&self, nick: &str) -> bool {
+        return self.info.casemapping.equals(nick, &self.config.nick);
+    }
+
+    fn get_room(&mut self, name: &str) -> Option<&mut Room> {
+        for room in &mut self.rooms {
+            if room.is_named(name) {
+                return Some(room);
+            }
+        }
+
+        return None;
+    }
+
+    fn update_status_line(&mut self) -> bool {
+        let new_status_line = self.status_line();
+        if new_status_line != self.status_line {
+            self.status_line = new_status_line;
+            return true;
+        }
+        return false;
+    }
+
+    fn status_line(&self) -> String {
+        let mut status = String::new();
+        for (i, room) in self.rooms.iter().enumerate() {
+            let s = if i == self.active_room {
+                room.status_string_active()
+            } else {
+                room.status_string()
+            };
+            status.push_str(&format!(" {} ", s));
+        }
+        for progress in self.dcc.transfers().iter().filter_map(|t| t.progress()) {
+            status.push_str(&format!(" dcc {} ", progress));
+        }
+        if self.queue.len() > 0 {
+            status.push_str(&format!(" queued {} ", self.queue.len()));
+        }
+        if self.stream.is_none() {
+            status.push_str(" (disconnected) ");
+        } else if let Some(lag) = self.current_lag() {
+            status.push_str(&format!(" lag {:.1}s ", lag as f64 / 1000000000.0));
+        }
+        return status;
+    }
+
+    /*
+     * The round-trip time of our last PING, or how long we've been waiting for the
+     * answer to the current one, if that's longer.
+     */
+    fn current_lag(&self) -> Option<u64> {
+        let waiting = self.ping_sent.map(|sent| time::precise_time_ns() - sent);
+        return match (self.lag, waiting) {
+            (Some(lag), Some(waiting)) => Some(if waiting > lag { waiting } else { lag }),
+            (lag, None)                => lag,
+            (None, waiting)            => waiting
+        };
+    }
+
+    fn should_update(&self) -> bool {
+        return self.active_room().should_update();
+    }
+
+    /*
+     * Let the client know that they should update the active room,
+     * and clear the updated flag.
+     */
+    fn update(&mut self) {
+        println!("UPDATE");
+        self.active_room_mut().clear_notify();
+    }
+
+    fn active_room(&self) -> &Room {
+        return &self.rooms[self.active_room];
+    }
+
+    fn active_room_mut(&mut self) -> &mut Room {
+        return &mut self.rooms[self.active_room];
+    }
+
+    fn server(&mut self) -> &mut Room {
+        return &mut self.rooms[0];
+    }
+
+    fn join_room(&mut self, name: &str) -> &mut Room {
+        self.rooms.push(Room::new(name, &self.config, &self.info));
+        self.active_room = self.rooms.len() - 1;
+        self.active_room().goto();
+        return &mut self.rooms[self.active_room];
+    }
+
+    /*
+     * Read the next message from the connection.
+     */
+    fn read_message(reader: &mut LineReader<Reader>) -> io::Result<Vec<u8>> {
+        let result = try!(reader.read_line());
+        if let Ok(s) = str::from_utf8(&result[..]) {
+            log!("RECEIVED: {}", s);
+        }
+        return Ok(result);
+    }
+
+    /*
+     * Exit with an error, which is shown in the server room as well.
+     */
+    fn die(&mut self, error: &str) {
+        log!("Error: {}", error);
+        self.server().warn(&format!("Error: {}", error));
+        println!("UPDATE");
+        println!("ERROR Error: {}", error);
+        process::exit(-1);
+    }
+
+    fn quit(&mut self, message: &str) {
+        self.send(&format!("QUIT :{}", message));
+
+        /*
+         * Make sure that the QUIT has actually been sent before we exit.
+         */
+        if let Some(stream) = self.stream.take() {
+            stream.close();
+        }
+
+        println!("QUIT");
+        process::exit(0);
+    }
+
+    /*
+     * Called when the connection to the server has been lost.
+     */
+    fn disconnected(&mut self, error: &str) {
+        /*
+         * We may have dropped the connection ourselves (see check_link), in which
+         * case everyone has already been told.
+         */
+        if self.stream.is_none() { return }
+
+        self.stream = None;
+        self.reader = None;
+        for room in &mut self.rooms {
+            room.warn(&format!("Disconnected from {}: {}", self.config.server, error));
+            room.clear_users();
+        }
+
+        /*
+         * What we meant for the old connection doesn't make sense on the next one.
+         */
+        let n = self.queue.clear();
+        if n > 0 {
+            self.server().warn(&format!("Dropped {} unsent line{}", n, if n == 1 { "" } else { "s" }));
+        }
+    }
+
+    /*
+     * Keep trying to reconnect, with exponential backoff, until we succeed. The
+     * lock is only held while a message is being handled, so the client stays
+     * responsive in the meantime.
+     */
+    fn reconnect(session: &Arc<Mutex<Session>>) {
+        let mut delay = RECONNECT_MIN_DELAY_MS;
+        loop {
+            let mut random = [0u8; 4];
+            let jitter = match rand_bytes(&mut random) {
+                Ok(()) => random.iter().fold(0u32, |n, b| (n << 8) | (*b as u32)) % (delay / 2 + 1),
+                Err(_) => 0
+            };
+
+            let wait = delay + jitter;
+            session.lock().unwrap().server().notify(&format!("Reconnecting in {:.1} seconds...", wait as f64 / 1000.0));
+            thread::sleep_ms(wait);
+
+            let result = Session::connect(session);
+            let mut session = session.lock().unwrap();
+            match result {
+                Ok(())                          => return,
+                /*
+                 * The server refused to let us in (e.g. SASL failed), and trying again won't help.
+                 */
+                Err(LoginError::Refused(e))    => session.die(&e),
+                /*
+                 * A slow server may do better next time; hang up and try again later.
+                 */
+                Err(LoginError::TimedOut(e))   => {
+                    if let Some(stream) = session.stream.take() {
+                        stream.close();
+                    }
+                    session.server().warn(&format!("Failed to reconnect: {}", e));
+                },
+                Err(LoginError::Lost(e))       => {
+                    session.server().warn(&format!("Failed to reconnect: {}", e));
+                }
+            }
+
+            delay = if delay >= RECONNECT_MAX_DELAY_MS / 2 { RECONNECT_MAX_DELAY_MS } else { delay * 2 };
+        }
+    }
+
+    /*
+     * Open a new connection to the server and log in again. Once registration has
+     * completed, every channel room that is still open is rejoined (see autojoin).
+     */
+    fn connect(session: &Mutex<Session>) -> Result<(), LoginError> {
+        let (host, port, tls, max_line_length) = {
+            let session = session.lock().unwrap();
+            let config = &session.config;
+            (config.host.clone(), config.port, config.tls.clone(), config.max_line_length)
+        };
+        let (stream, reader) = try!(Connection::open(&host, port, tls.as_ref()).map_err(LoginError::Lost));
+        let mut reader = LineReader::new(reader, max_line_length);
+
+        session.lock().unwrap().start_over(stream);
+
+        try!(Session::log_in(session, &mut reader));
+
+        let mut session = session.lock().unwrap();
+        session.reader = Some(reader);
+        let notification = format!("Reconnected to {}", session.config.server);
+        session.server().notify(&notification);
+        return Ok(());
+    }
+
+    /*
+     * Rooms and channels compare names with a casemapping of their own; keep it the
+     * same as the server's.
+     */
+    fn update_casemapping(&mut self) {
+        let casemapping = self.info.casemapping;
+        for room in &mut self.rooms {
+            room.set_casemapping(casemapping);
+        }
+    }
+
+    /*
+     * Forget about the last connection, and get ready to register over `stream`.
+     */
+    fn start_over(&mut self, stream: Connection) {
+        self.stream = Some(stream);
+        self.caps = Caps::new(self.config.caps.clone());
+        self.info = ServerInfo::new();
+        self.update_casemapping();
+        self.nickserv = if self.config.auth == AuthMethod::NickServ {
+            NickServState::Registering
+        } else {
+            NickServState::Unused
+        };
+        self.autojoined = false;
+        self.autojoin_held = false;
+        self.rejoin = true;
+        self.registered = false;
+        self.our_mask = None;
+        self.whois.clear();
+        self.last_activity = time::precise_time_ns();
+        self.last_ping = self.last_activity;
+        self.ping_sent = None;
+        self.lag = None;
+    }
+
+    /*
+     * Negotiate capabilities, identify, optionally authenticate, and wait for the
+     * server to welcome us.
+     */
+    fn log_in(session: &Mutex<Session>, reader: &mut LineReader<Reader>) -> Result<(), LoginError> {
+        try!(Session::register(session, reader));
+
+        /*
+         * NickServ identification happens once registration has completed.
+         */
+        let sasl = match session.lock().unwrap().config.auth {
+            AuthMethod::SASL | AuthMethod::External   => true,
+            AuthMethod::NoAuth | AuthMethod::NickServ => false
+        };
+        if sasl {
+            if let Err(e) = Session::auth_sasl(session, reader) {
+                return Err(e.map(|e| format!("failed to authenticate: {}", e)));
+            }
+        }
+
+        let (registered, timeout) = {
+            let mut session = session.lock().unwrap();
+            session.end_cap_negotiation();
+            (session.registered, session.config.registration_timeout)
+        };
+
+        if !registered {
+            let deadline = Instant::now() + Duration::from_secs(timeout);
+            let welcomed = try!(Session::wait_until(session, reader, deadline, |session, _| if session.registered { Some(()) } else { None }));
+            if welcomed.is_none() {
+                return Err(LoginError::TimedOut(format!("the server did not complete our registration within {} seconds", timeout)));
+            }
+        }
+
+        return Ok(());
     }
 
     fn part(&mut self, message: &str) {
@@ -552,7 +2119,7 @@ impl Session {
              * If we're in a channel, send a PART message; otherwise, we
              * are in a private chat and therefore do not need to send one.
              */
-            if target.is_channel_name() {
+            if target.is_channel_name(&self.info) {
                 self.send(&format!("PART {} :{}", target, message));
             }
 
@@ -569,50 +2136,184 @@ impl Session {
         }
     }
 
-    fn auth_sasl(&mut self) -> Option<String> {
-        self.send("CAP REQ :sasl");
+    /*
+     * Start capability negotiation and send NICK / USER, then wait until every
+     * capability we asked for has been ACKed or NAKed.
+     *
+     * CAP END is sent separately (see end_cap_negotiation), so that we can
+     * authenticate before the server completes our registration. If negotiation
+     * doesn't finish within registration_timeout, we carry on without capabilities,
+     * unless we need them for SASL.
+     */
+    fn register(session: &Mutex<Session>, reader: &mut LineReader<Reader>) -> Result<(), LoginError> {
+        use ircnvim::irc::IrcMessageType::*;
+
+        let timeout = {
+            let mut session = session.lock().unwrap();
+            session.caps.state = CapState::Listing;
+            session.send("CAP LS 302");
+            session.identify();
+            session.config.registration_timeout
+        };
 
-        self.identify();
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        let negotiated = try!(Session::wait_until(session, reader, deadline, |session, message| {
+            match message.kind {
+                /*
+                 * The server doesn't support capability negotiation at all.
+                 */
+                Welcome                                          => {
+                    session.caps.state = CapState::Idle;
+                    Some(false)
+                },
+                UnknownCommand if message.sequence(1) == "CAP"     => {
+                    session.caps.state = CapState::Idle;
+                    Some(false)
+                },
+                _ if session.caps.state == CapState::Done        => Some(true),
+                _                                                => None
+            }
+        }));
 
-        if !self.wait_for_or("ACK :sasl", "NAK :sasl") {
-            return Some(format!("the ircd at {} does not support SASL", self.config.server));
+        if negotiated.is_none() {
+            let mut session = session.lock().unwrap();
+            if session.config.auth == AuthMethod::SASL || session.config.auth == AuthMethod::External {
+                return Err(LoginError::TimedOut(format!("capability negotiation did not finish within {} seconds, so we can't use SASL", timeout)));
+            }
+            session.server().warn(&format!("Capability negotiation did not finish within {} seconds; continuing without capabilities", timeout));
+            session.send("CAP END");
+            session.caps.state = CapState::Idle;
         }
 
-        self.send("AUTHENTICATE PLAIN");
-        if !self.wait_for("AUTHENTICATE +") {
-            return Some(format!("timed out waiting for reponse"));
+        return Ok(());
+    }
+
+    fn end_cap_negotiation(&mut self) {
+        if self.caps.state != CapState::Idle {
+            self.send("CAP END");
         }
+    }
+
+    fn auth_sasl(session: &Mutex<Session>, reader: &mut LineReader<Reader>) -> Result<(), LoginError> {
+        let (mechanism, mut exchange, timeout) = {
+            let mut session = session.lock().unwrap();
+            if !session.has_cap("sasl") {
+                if session.caps.is_available("sasl") {
+                    return Err(LoginError::Refused(format!("the ircd at {} refused to enable SASL", session.config.server)));
+                }
+                return Err(LoginError::Refused(format!("the ircd at {} does not support SASL", session.config.server)));
+            }
+
+            /*
+             * Unless the profile asks for a particular mechanism, use the best one the
+             * server advertises in the value of its sasl capability.
+             */
+            let advertised = session.caps.value("sasl").map(|s| s.to_string());
+            let mechanism = match session.config.auth {
+                AuthMethod::External => Mechanism::External,
+                _                    => session.config.sasl_mechanism.unwrap_or(Mechanism::choose(advertised.as_ref().map(|s| &s[..])))
+            };
+
+            if let Some(ref list) = advertised {
+                if !mechanism.is_in(list) {
+                    return Err(LoginError::Refused(format!("the server does not support SASL {}; it supports {}", mechanism.name(), list)));
+                }
+            }
+
+            let exchange = {
+                let password = session.config.password.as_ref().map(|s| &s[..]);
+                Exchange::new(mechanism, &session.config.username, password)
+            };
 
-        let auth_string = {
-            let username = &self.config.username[..];
-            let password = self.config.password.as_ref().map(|s| &s[..]).unwrap();
-            format!("{}\0{}\0{}", username, username, password)
-                .as_bytes()
-                .to_base64(base64::Config {
-                    char_set:    base64::CharacterSet::Standard,
-                    newline:     base64::Newline::CRLF,
-                    pad:         true,
-                    line_length: None
-                })
+            session.send(&format!("AUTHENTICATE {}", mechanism.name()));
+            (mechanism, exchange, session.config.auth_timeout)
         };
 
-        self.send(&format!("AUTHENTICATE {}", &auth_string));
+        let deadline = Instant::now() + Duration::from_secs(timeout);
 
-        if !self.wait_for_or("authentication successful", "authentication failed") {
-            return Some(format!("invalid username/password combination"));
+        /*
+         * Challenges longer than CHUNK_SIZE bytes arrive in several AUTHENTICATE
+         * messages, so they are collected until a shorter piece (or "+") arrives.
+         */
+        let mut challenge = String::new();
+        loop {
+            match try!(Session::sasl_reply(session, reader, deadline, timeout)) {
+                SaslReply::Challenge(data) => {
+                    if data != "+" {
+                        challenge.push_str(&data);
+                    }
+                    if data.len() == sasl::CHUNK_SIZE { continue }
+
+                    let response = sasl::decode(&challenge).and_then(|c| exchange.respond(&c[..]));
+                    challenge.clear();
+
+                    let mut session = session.lock().unwrap();
+                    match response {
+                        Ok(response) => {
+                            for chunk in sasl::chunks(&sasl::encode(&response[..])) {
+                                session.send(&format!("AUTHENTICATE {}", chunk));
+                            }
+                        },
+                        Err(e)       => {
+                            session.send("AUTHENTICATE *");
+                            return Err(LoginError::Refused(e));
+                        }
+                    }
+                },
+                SaslReply::Success         => {
+                    if !exchange.is_complete() {
+                        return Err(LoginError::Refused(format!("the server accepted SASL {} without completing the exchange", mechanism.name())));
+                    }
+                    return Ok(());
+                },
+                SaslReply::Failure(reason) => return Err(LoginError::Refused(reason)),
+                SaslReply::Mechanisms(_)   => { }
+            }
         }
+    }
 
-        self.send("CAP END");
-
-        return None;
+    /*
+     * Wait for the server's next SASL reply: either a challenge, or the outcome of
+     * the exchange. If the server lists the mechanisms it supports (908), they are
+     * included in the failure that follows.
+     */
+    fn sasl_reply(session: &Mutex<Session>, reader: &mut LineReader<Reader>, deadline: Instant, timeout: u64) -> Result<SaslReply, LoginError> {
+        let mut mechanisms = None;
+        loop {
+            let reply = try!(Session::wait_until(session, reader, deadline, |_, message| sasl::classify(message)));
+            match try!(reply.ok_or(LoginError::TimedOut(format!("the server did not finish the exchange within {} seconds", timeout)))) {
+                SaslReply::Mechanisms(list) => mechanisms = Some(list),
+                SaslReply::Failure(reason)  => {
+                    return Ok(SaslReply::Failure(match mechanisms {
+                        Some(list) => format!("{}; the server supports {}", reason, list),
+                        None       => reason
+                    }));
+                },
+                reply                       => return Ok(reply)
+            }
+        }
     }
 
-    fn auth_nickserv(&mut self) -> Option<String> {
-        None
+    /*
+     * Send IDENTIFY to NickServ. The reply is handled as it arrives (see
+     * handle_nickserv_notice), and tick gives up on it after auth_timeout seconds.
+     */
+    fn auth_nickserv(&mut self) {
+        let identify = format!(
+            "PRIVMSG NickServ :IDENTIFY {} {}",
+            self.config.nickserv_account,
+            self.config.password.as_ref().map(|s| &s[..]).unwrap()
+        );
+        self.send(&identify);
+        self.nickserv = NickServState::Waiting(time::precise_time_ns() + self.config.auth_timeout * 1000000000);
     }
 
     fn identify(&mut self) -> Option<String> {
-        let nick_string = format!("NICK {}", self.config.nick);
+        /*
+         * After a reconnect, we try to get our primary nick back straight away.
+         */
+        let nick = if self.reclaim_nick { &self.primary_nick } else { &self.config.nick };
+        let nick_string = format!("NICK {}", nick);
         self.send(&nick_string);
 
         let user_string = format!("USER {} {} _ :{}", self.config.username, "_", "_");
@@ -621,63 +2322,233 @@ impl Session {
         return None;
     }
 
+    /*
+     * Send a line to the server, once the send queue lets it through. Until we're
+     * registered, and for the few commands that can't wait, it goes out right away.
+     */
     fn send(&mut self, text: &str) {
+        self.send_echoed(text, None);
+    }
+
+    /*
+     * The same as send, and once the line has actually gone out, show `echo`.
+     */
+    fn send_echoed(&mut self, text: &str, echo: Option<Echo>) {
+        if !self.registered || queue::is_urgent(text) {
+            self.write_line(text);
+            if let Some(echo) = echo {
+                self.show_echo(echo);
+            }
+        } else {
+            self.queue.push(Outgoing { line: text.to_string(), echo: echo });
+            self.flush_queue();
+        }
+    }
+
+    /*
+     * Send whatever the send queue lets through by now.
+     */
+    fn flush_queue(&mut self) {
+        while let Some(outgoing) = self.queue.pop(Instant::now()) {
+            self.write_line(&outgoing.line);
+            if let Some(echo) = outgoing.echo {
+                self.show_echo(echo);
+            }
+        }
+    }
+
+    fn show_echo(&mut self, echo: Echo) {
+        let Echo { room, source, body } = echo;
+        if let Some(room) = self.get_room(&room) {
+            room.add_message(Message::new(source, body));
+        }
+    }
+
+    /*
+     * Write a line to the server. While we're disconnected, it is dropped.
+     */
+    fn write_line(&mut self, text: &str) {
         log!("SENDING: {}", text);
-        self.stream.write(text.as_bytes()).unwrap();
-        self.stream.write(b"\r\n").unwrap();
+        if let Some(ref mut stream) = self.stream {
+            if let Err(e) = stream.write(text.as_bytes()).and_then(|_| stream.write(b"\r\n")) {
+                log!("Error sending: {}", e);
+            }
+        }
     }
 
     /*
-     * Wait until an incoming message matches either `good` or `bad` (or we time out).
+     * Read and handle incoming messages until `done` returns Some(result) for one of
+     * them, and then return that result. `done` is called after the message has been
+     * handled, so it sees the updated session.
      *
-     * good          -> true
-     * bad / timeout -> false
+     * If `deadline` passes first, None is returned. If the connection is lost in the
+     * meantime, or the server sends ERROR to tell us why it is about to close it, the
+     * error is returned.
      */
-    fn wait_for_or(&mut self, good: &str, bad: &str) -> bool {
-        let mut buf = [0u8; BUFFER_SIZE];
-        let mut idx = 0usize;
+    fn wait_until<T, F>(session: &Mutex<Session>, reader: &mut LineReader<Reader>, deadline: Instant, mut done: F) -> Result<Option<T>, LoginError> where F: FnMut(&mut Session, &IrcMessage) -> Option<T> {
         loop {
-            match Session::read_message(&mut self.stream, &mut buf, &mut idx).and_then(|bs| IrcMessage::parse(&bs[..])) {
+            /*
+             * Nothing is locked while we wait.
+             */
+            reader.get_mut().set_deadline(Some(deadline));
+            let bytes = Session::read_message(reader);
+            reader.get_mut().set_deadline(None);
+
+            let mut session = session.lock().unwrap();
+            let bytes = match bytes {
+                Ok(bytes)                                             => bytes,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut    => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    session.server().warn(&format!("Dropped a message: {}", e));
+                    continue;
+                },
+                Err(e)                                                => {
+                    let e = e.to_string();
+                    session.disconnected(&e);
+                    return Err(LoginError::Lost(e));
+                }
+            };
+            match IrcMessage::parse(&bytes[..]) {
                 Ok(msg) => {
                     msg.log();
-                    let good = msg.contains(good);
-                    let bad = msg.contains(bad);
-                    self.handle_message(msg);
-                    if good { return true }
-                    if bad { return false }
+                    session.handle_message(&msg);
+                    if let IrcMessageType::Error = msg.kind {
+                        let reason = msg.sequence(0);
+                        session.disconnected(&reason);
+                        return Err(LoginError::Lost(format!("the server closed the connection: {}", reason)));
+                    }
+                    if let Some(result) = done(&mut *session, &msg) { return Ok(Some(result)) }
                 },
                 Err(e)  => {
-                    self.die(&e);
+                    session.server().warn(&format!("Dropped a message: {}", e));
                 }
             }
         }
+    }
+}
 
-        return false;
+/*
+ * (Re)create the global regex used to highlight messages that mention our nick.
+ *
+ * It is only used while the session is locked, so the old one can be freed.
+ */
+fn set_nick_regex(nick: &str) {
+    let regex = Regex::new(&format!(r"([^A-Z]|\b){}([^A-Z]|\b)", regex::quote(nick))).unwrap();
+    unsafe {
+        let old = _my_nick_regex;
+        _my_nick_regex = Box::into_raw(Box::new(regex));
+        if !old.is_null() {
+            drop(Box::from_raw(old as *mut Regex));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ircnvim::config::Config;
+    use std::env;
+    use std::io::{BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    /*
+     * A session connected to a stand-in server, which passes on every line the session
+     * sends it. Also returns the session's directory.
+     */
+    fn session(nick: &str) -> (Session, Receiver<String>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (lines_tx, lines) = channel();
+        thread::spawn(move || {
+            let tcp = listener.accept().unwrap().0;
+            for line in BufReader::new(tcp).lines() {
+                match line {
+                    Ok(line) => if lines_tx.send(line).is_err() { return },
+                    Err(_)   => return
+                }
+            }
+        });
+
+        let directory = env::temp_dir().join(format!("ircnvim-session-test-{}", nick)).to_str().unwrap().to_string();
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let profile = format!("test\nserver 127.0.0.1:{}\nnick {}\nusername {}\ndcc_address 127.0.0.1\n\n", port, nick, nick);
+        File::create(format!("{}/config", directory)).unwrap().write_all(profile.as_bytes()).unwrap();
+
+        let config = Config::load(directory.clone(), None).unwrap();
+        return (Session::new(config).unwrap(), lines, directory);
     }
 
     /*
-     * Wait until either an incoming message matches either `good`, or we time out.
-     *
-     * good    -> true
-     * timeout -> false
+     * The next CTCP DCC message the session sent.
      */
-    fn wait_for(&mut self, good: &str) -> bool {
-        let mut buf = [0u8; BUFFER_SIZE];
-        let mut idx = 0usize;
+    fn next_dcc(lines: &Receiver<String>) -> String {
         loop {
-            match Session::read_message(&mut self.stream, &mut buf, &mut idx).and_then(|bs| IrcMessage::parse(&bs[..])) {
-                Ok(msg) => {
-                    msg.log();
-                    let good = msg.contains(good);
-                    self.handle_message(msg);
-                    if good { return true }
-                },
-                Err(e)  => {
-                    self.die(&e);
-                }
+            let line = lines.recv_timeout(Duration::from_secs(10)).expect("line");
+            if line.contains("\x01DCC ") {
+                return line;
             }
         }
+    }
 
-        return false;
+    /*
+     * Pass something one session sent on to another, as the server would.
+     */
+    fn deliver(session: &mut Session, from: &str, line: &str) {
+        let relayed = format!(":{}!{}@localhost {}", from, from, line);
+        session.handle_message(&IrcMessage::parse(relayed.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_dcc_resume() {
+        let (mut alice, alice_lines, alice_directory) = session("alice");
+        let (mut bob, bob_lines, bob_directory) = session("bob");
+
+        let contents = (0..100000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let source = format!("{}/log.txt", alice_directory);
+        File::create(&source).unwrap().write_all(&contents).unwrap();
+
+        /*
+         * Bob already has the beginning of the file.
+         */
+        let downloads = format!("{}/downloads", bob_directory);
+        fs::create_dir_all(&downloads).unwrap();
+        File::create(format!("{}/log.txt", downloads)).unwrap().write_all(&contents[..30000]).unwrap();
+
+        /*
+         * The offer arrives with a name that tries to get out of dcc_directory.
+         */
+        alice.dcc_command(&format!("send bob {}", source));
+        let offer = next_dcc(&alice_lines).replace("SEND log.txt ", "SEND ../../log.txt ");
+        assert!(offer.starts_with("PRIVMSG bob :\x01DCC SEND ../../log.txt 2130706433 "));
+        assert!(offer.ends_with(" 100000\x01"));
+        deliver(&mut bob, "alice", &offer);
+
+        bob.dcc_command("accept");
+        let resume = next_dcc(&bob_lines);
+        assert!(resume.starts_with("PRIVMSG alice :\x01DCC RESUME log.txt "));
+        assert!(resume.ends_with(" 30000\x01"));
+        deliver(&mut alice, "bob", &resume);
+
+        let accept = next_dcc(&alice_lines);
+        assert!(accept.starts_with("PRIVMSG bob :\x01DCC ACCEPT log.txt "));
+        deliver(&mut bob, "alice", &accept);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !alice.dcc.transfers().is_empty() || !bob.dcc.transfers().is_empty() {
+            assert!(Instant::now() < deadline, "the transfer did not finish");
+            alice.handle_dcc_events();
+            bob.handle_dcc_events();
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut received = Vec::new();
+        File::open(format!("{}/log.txt", downloads)).unwrap().read_to_end(&mut received).unwrap();
+        assert_eq!(received, contents);
+
+        let _ = fs::remove_dir_all(&alice_directory);
+        let _ = fs::remove_dir_all(&bob_directory);
     }
 }
@@ -0,0 +1,204 @@
+use ircnvim::text::{TextChunk, is_format_code};
+
+/*
+ * A line can be at most 512 bytes long, CRLF included. Message tags don't count
+ * towards that; they have a limit of their own.
+ */
+pub const MAX_LINE_LENGTH: usize = 512;
+
+/*
+ * Until we learn our host, we assume it is as long as a host name can be.
+ */
+pub const MAX_HOST_LENGTH: usize = 63;
+
+const ACTION_START: &'static str = "\x01ACTION ";
+const ACTION_END: &'static str = "\x01";
+
+/*
+ * The PRIVMSGs that send `text` to `target`, along with the piece of text each of
+ * them carries. `prefix_length` is the length of the ":nick!user@host " that the
+ * server puts in front of the message when it relays it, which also has to fit.
+ */
+pub fn privmsgs(target: &str, text: &str, prefix_length: usize, action: bool) -> Vec<(String, String)> {
+    let overhead = prefix_length + "PRIVMSG ".len() + target.len() + " :".len() + "\r\n".len();
+    let framing = if action { ACTION_START.len() + ACTION_END.len() } else { 0 };
+    let budget = MAX_LINE_LENGTH.saturating_sub(overhead + framing);
+
+    return split(text, budget).into_iter().map(|piece| {
+        let line = if action {
+            format!("PRIVMSG {} :{}{}{}", target, ACTION_START, piece, ACTION_END)
+        } else {
+            format!("PRIVMSG {} :{}", target, piece)
+        };
+        (line, piece)
+    }).collect();
+}
+
+/*
+ * Split `text` into pieces of at most `max` bytes, preferably at spaces (which are
+ * dropped), and never inside a UTF-8 sequence or a formatting code. Every piece
+ * but the first starts with the codes that bring back the formatting in effect
+ * where it was cut, since clients reset it at the start of each message.
+ */
+pub fn split(text: &str, max: usize) -> Vec<String> {
+    let atoms = atoms(text);
+    let is_space = |i: usize| text.as_bytes()[atoms[i].0] == b' ';
+    let mut pieces = Vec::new();
+    let mut first = 0;
+
+    /*
+     * With no room at all, send everything as it is and let the server truncate it.
+     */
+    if max == 0 || atoms.is_empty() {
+        return vec![text.to_string()];
+    }
+
+    loop {
+        let mut codes = if first == 0 { String::new() } else { atoms[first - 1].2.codes() };
+        if codes.len() >= max {
+            codes.clear();
+        }
+        let budget = max - codes.len();
+        let start = atoms[first].0;
+
+        if text.len() - start <= budget {
+            pieces.push(codes + &text[start..]);
+            break;
+        }
+
+        /*
+         * The first atom that doesn't fit.
+         */
+        let mut end = first;
+        while atoms[end].1 - start <= budget {
+            end += 1;
+        }
+
+        let space = if is_space(end) { Some(end) } else { (first + 1..end).rev().find(|&i| is_space(i)) };
+        match space {
+            Some(space) if space > first => {
+                pieces.push(codes + &text[start..atoms[space].0]);
+                first = space + 1;
+            },
+            _                            => {
+                /*
+                 * A single atom longer than the budget can't be split.
+                 */
+                let end = if end == first { first + 1 } else { end };
+                pieces.push(codes + &text[start..atoms[end - 1].1]);
+                first = end;
+            }
+        }
+
+        if first >= atoms.len() {
+            break;
+        }
+    }
+
+    return pieces;
+}
+
+/*
+ * The pieces `text` can't be cut inside of (characters, and formatting codes along
+ * with their colors), as (start, end, formatting after it).
+ */
+fn atoms(text: &str) -> Vec<(usize, usize, TextChunk)> {
+    let bytes = text.as_bytes();
+    let mut format = TextChunk::plain(String::new());
+    let mut atoms = Vec::new();
+
+    for (i, c) in text.char_indices() {
+        if atoms.last().map(|&(_, end, _)| i < end).unwrap_or(false) {
+            continue;
+        }
+
+        let end = if is_format_code(bytes[i]) {
+            i + 1 + format.apply(bytes[i], &bytes[i + 1..])
+        } else {
+            i + c.len_utf8()
+        };
+        atoms.push((i, end, format.clone()));
+    }
+
+    return atoms;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split("hello world", 20), vec!["hello world"]);
+        assert_eq!(split("hello world", 11), vec!["hello world"]);
+        assert_eq!(split("hello world", 10), vec!["hello", "world"]);
+        assert_eq!(split("hello world", 5), vec!["hello", "world"]);
+        assert_eq!(split("one two three four", 9), vec!["one two", "three", "four"]);
+        assert_eq!(split("", 5), vec![""]);
+    }
+
+    #[test]
+    fn test_split_long_words() {
+        assert_eq!(split("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(split("ab cdefghij", 4), vec!["ab", "cdef", "ghij"]);
+    }
+
+    #[test]
+    fn test_split_utf8() {
+        /*
+         * é is two bytes, and € three.
+         */
+        assert_eq!(split("éééé", 3), vec!["é", "é", "é", "é"]);
+        assert_eq!(split("€€", 4), vec!["€", "€"]);
+        assert_eq!(split("€€", 2), vec!["€", "€"]);
+        for piece in split("héllo wörld, ça va? €uro", 4) {
+            assert!(piece.len() <= 4);
+        }
+    }
+
+    #[test]
+    fn test_split_formatting() {
+        /*
+         * Colors aren't cut off from their digits.
+         */
+        assert_eq!(split("ab\x0304cd", 4), vec!["ab", "\x0304c", "\x0304d"]);
+        assert_eq!(split("\x04FF8800abc def", 10), vec!["\x04FF8800abc", "\x04FF8800def"]);
+
+        /*
+         * Whatever was in effect carries over, and stops carrying over once reset.
+         */
+        assert_eq!(split("\x02\x034,2bold words", 12), vec!["\x02\x034,2bold", "\x02\x0304,02words"]);
+        assert_eq!(split("\x1Dab\x0F cd", 4), vec!["\x1Dab\x0F", "cd"]);
+        for piece in split("\x02\x0312,04\x1F\x1Dwords that are formatted", 10) {
+            assert!(piece.len() <= 10);
+        }
+    }
+
+    #[test]
+    fn test_privmsgs() {
+        let prefix = ":nick!user@host ".len();
+        let text = (0..200).map(|_| "word").collect::<Vec<_>>().join(" ");
+        let messages = privmsgs("#channel", &text, prefix, false);
+        assert!(messages.len() > 1);
+        for &(ref line, ref piece) in &messages {
+            assert!(prefix + line.len() + 2 <= MAX_LINE_LENGTH);
+            assert_eq!(line, &format!("PRIVMSG #channel :{}", piece));
+        }
+        let pieces = messages.iter().map(|&(_, ref piece)| &piece[..]).collect::<Vec<_>>();
+        assert_eq!(pieces.join(" "), text);
+    }
+
+    #[test]
+    fn test_actions() {
+        let prefix = ":nick!user@host ".len();
+        let text = (0..1000).map(|_| "x").collect::<String>();
+        let messages = privmsgs("#channel", &text, prefix, true);
+        assert_eq!(messages.len(), 3);
+        for &(ref line, ref piece) in &messages {
+            assert!(prefix + line.len() + 2 <= MAX_LINE_LENGTH);
+            assert_eq!(line, &format!("PRIVMSG #channel :\x01ACTION {}\x01", piece));
+        }
+        assert_eq!(messages[0].1.len(), 512 - 2 - prefix - "PRIVMSG #channel :\x01ACTION \x01".len());
+    }
+}
@@ -2,15 +2,167 @@ use std::io::Write;
 use std::io;
 use std::str;
 
+/*
+ * A color set with \x03 (from the 99-color palette) or \x04 (as RGB).
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Default,
+    Palette(u8),
+    Rgb(u8, u8, u8)
+}
+
+/*
+ * The RGB values of the palette colors: the 16 classic ones, followed by the 83
+ * extended ones. Color 99 means the default color.
+ */
+static PALETTE: [u32; 99] = [
+    0xffffff, 0x000000, 0x00007f, 0x009300, 0xff0000, 0x7f0000, 0x9c009c, 0xfc7f00,
+    0xffff00, 0x00fc00, 0x009393, 0x00ffff, 0x0000fc, 0xff00ff, 0x7f7f7f, 0xd2d2d2,
+    0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472c, 0x004747, 0x002747, 0x000047, 0x2e0047, 0x470047, 0x47002a,
+    0x740000, 0x743a00, 0x747400, 0x517400, 0x007400, 0x007449, 0x007474, 0x004074, 0x000074, 0x4b0074, 0x740074, 0x740045,
+    0xb50000, 0xb56300, 0xb5b500, 0x7db500, 0x00b500, 0x00b571, 0x00b5b5, 0x0063b5, 0x0000b5, 0x7500b5, 0xb500b5, 0xb5006b,
+    0xff0000, 0xff8c00, 0xffff00, 0xb2ff00, 0x00ff00, 0x00ffa0, 0x00ffff, 0x008cff, 0x0000ff, 0xa500ff, 0xff00ff, 0xff0098,
+    0xff5959, 0xffb459, 0xffff71, 0xcfff60, 0x6fff6f, 0x65ffc9, 0x6dffff, 0x59b4ff, 0x5959ff, 0xc459ff, 0xff66ff, 0xff59bc,
+    0xff9c9c, 0xffd39c, 0xffff9c, 0xe2ff9c, 0x9cff9c, 0x9cffdb, 0x9cffff, 0x9cd3ff, 0x9c9cff, 0xdc9cff, 0xff9cff, 0xff94d3,
+    0x000000, 0x131313, 0x282828, 0x363636, 0x4d4d4d, 0x656565, 0x818181, 0x9f9f9f, 0xbcbcbc, 0xe2e2e2, 0xffffff
+];
+
+impl Color {
+    fn palette(n: u8) -> Color {
+        if n >= 99 {
+            return Color::Default;
+        } else {
+            return Color::Palette(n);
+        }
+    }
+
+    /*
+     * The color as RGB, or None for the default color.
+     */
+    pub fn rgb(&self) -> Option<(u8, u8, u8)> {
+        return match *self {
+            Color::Default       => None,
+            Color::Palette(n)    => {
+                let c = PALETTE[n as usize];
+                Some(((c >> 16) as u8, (c >> 8) as u8, c as u8))
+            },
+            Color::Rgb(r, g, b)  => Some((r, g, b))
+        };
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TextChunk {
     pub text: String,
-    pub fg: u8,
-    pub bg: u8,
+    pub fg: Color,
+    pub bg: Color,
     pub bold: bool,
     pub italic: bool,
     pub underline: bool,
     pub reverse: bool,
+    pub monospace: bool,
+    pub strikethrough: bool
+}
+
+impl TextChunk {
+    pub fn plain(text: String) -> TextChunk {
+        return TextChunk {
+            text: text,
+            fg: Color::Default,
+            bg: Color::Default,
+            bold: false,
+            italic: false,
+            underline: false,
+            reverse: false,
+            monospace: false,
+            strikethrough: false
+        };
+    }
+
+    /*
+     * Change the formatting as the control code `c` says. `rest` is what follows the
+     * code; returns how many bytes of it (color digits) belong to the code.
+     */
+    pub fn apply(&mut self, c: u8, rest: &[u8]) -> usize {
+        match c {
+            0x02 => { self.bold = !self.bold },
+            0x1D => { self.italic = !self.italic },
+            0x1F => { self.underline = !self.underline },
+            0x16 => { self.reverse = !self.reverse },
+            0x11 => { self.monospace = !self.monospace },
+            0x1E => { self.strikethrough = !self.strikethrough },
+            0x0F => { *self = TextChunk::plain(String::new()) },
+            0x03 | 0x04 => {
+                let (fg, bg, n) = if c == 0x03 { parse_color(rest) } else { parse_hex_color(rest) };
+                match (fg, bg) {
+                    (Some(f), Some(b)) => { self.fg = f; self.bg = b },
+                    (Some(f), None)    => { self.fg = f },
+                    _                  => { self.fg = Color::Default; self.bg = Color::Default }
+                }
+                return n;
+            },
+            _    => { }
+        }
+        return 0;
+    }
+
+    /*
+     * The control codes that turn plain text into text formatted like this chunk.
+     */
+    pub fn codes(&self) -> String {
+        let mut codes = String::new();
+        for &(set, code) in &[(self.bold, "\x02"), (self.italic, "\x1D"), (self.underline, "\x1F"),
+                              (self.reverse, "\x16"), (self.monospace, "\x11"), (self.strikethrough, "\x1E")] {
+            if set {
+                codes.push_str(code);
+            }
+        }
+
+        /*
+         * Palette colors are given as two digits, so that digits in the text that
+         * follows can't be mistaken for part of them.
+         */
+        let number = |c: Color| match c {
+            Color::Palette(n) => Some(n),
+            Color::Default    => Some(99),
+            Color::Rgb(..)    => None
+        };
+        let hex = |c: Color| c.rgb().map(|(r, g, b)| format!("{:02X}{:02X}{:02X}", r, g, b));
+        match (self.fg, self.bg, number(self.fg), number(self.bg)) {
+            (Color::Default, Color::Default, _, _) => { },
+            (_, Color::Default, Some(fg), _)       => codes.push_str(&format!("\x03{:02}", fg)),
+            (_, _, Some(fg), Some(bg))             => codes.push_str(&format!("\x03{:02},{:02}", fg, bg)),
+            (fg, bg, _, _)                         => {
+                match (hex(fg), hex(bg)) {
+                    (Some(fg), Some(bg)) => codes.push_str(&format!("\x04{},{}", fg, bg)),
+                    (Some(fg), None)     => codes.push_str(&format!("\x04{}", fg)),
+                    (None, _)            => { }
+                }
+            }
+        }
+        return codes;
+    }
+
+    /*
+     * e.g., ["fg=ff0000", "b", "u"], or nothing if the chunk isn't formatted.
+     */
+    fn attributes(&self) -> Vec<String> {
+        let mut attributes = Vec::new();
+        if let Some((r, g, b)) = self.fg.rgb() {
+            attributes.push(format!("fg={:02x}{:02x}{:02x}", r, g, b));
+        }
+        if let Some((r, g, b)) = self.bg.rgb() {
+            attributes.push(format!("bg={:02x}{:02x}{:02x}", r, g, b));
+        }
+        for &(set, name) in &[(self.bold, "b"), (self.italic, "i"), (self.underline, "u"),
+                              (self.reverse, "r"), (self.monospace, "m"), (self.strikethrough, "s")] {
+            if set {
+                attributes.push(name.to_string());
+            }
+        }
+        return attributes;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -21,10 +173,81 @@ pub struct Text {
     pub action: bool
 }
 
-const DEFAULT_COLOR: u8 = 255;
+/*
+ * Whether `b` is one of the control codes that format text.
+ */
+pub fn is_format_code(b: u8) -> bool {
+    return match b {
+        0x02 | 0x03 | 0x04 | 0x0F | 0x11 | 0x16 | 0x1D | 0x1E | 0x1F => true,
+        _                                                            => false
+    };
+}
+
+/*
+ * The number of decimal digits (at most `max`) at the start of `bytes`.
+ */
+fn count_digits(bytes: &[u8], max: usize) -> usize {
+    return bytes.iter().take(max).take_while(|&&b| b >= b'0' && b <= b'9').count();
+}
+
+fn decimal(digits: &[u8]) -> u8 {
+    return digits.iter().fold(0, |n, d| n * 10 + (d - b'0'));
+}
+
+/*
+ * Parse the colors following \x03: one or two digits for the foreground, then
+ * optionally a comma and one or two digits for the background. A comma that isn't
+ * followed by a digit belongs to the text. Returns the colors, and how many bytes
+ * they took up. Without a foreground, the colors are reset.
+ */
+fn parse_color(bytes: &[u8]) -> (Option<Color>, Option<Color>, usize) {
+    let n = count_digits(bytes, 2);
+    if n == 0 {
+        return (None, None, 0);
+    }
+
+    let fg = Color::palette(decimal(&bytes[..n]));
+
+    if bytes.get(n) == Some(&b',') {
+        let m = count_digits(&bytes[n+1..], 2);
+        if m > 0 {
+            return (Some(fg), Some(Color::palette(decimal(&bytes[n+1..n+1+m]))), n + 1 + m);
+        }
+    }
+
+    return (Some(fg), None, n);
+}
+
+fn parse_rgb(bytes: &[u8]) -> Option<Color> {
+    if bytes.len() < 6 {
+        return None;
+    }
+    let digits = match str::from_utf8(&bytes[..6]) {
+        Ok(digits) => digits,
+        Err(_)     => return None
+    };
+    return match u32::from_str_radix(digits, 16) {
+        Ok(c) if !digits.starts_with('+') => Some(Color::Rgb((c >> 16) as u8, (c >> 8) as u8, c as u8)),
+        _                                 => None
+    };
+}
+
+/*
+ * The same as parse_color, for the RRGGBB colors following \x04.
+ */
+fn parse_hex_color(bytes: &[u8]) -> (Option<Color>, Option<Color>, usize) {
+    let fg = match parse_rgb(bytes) {
+        Some(fg) => fg,
+        None     => return (None, None, 0)
+    };
+
+    if bytes.get(6) == Some(&b',') {
+        if let Some(bg) = parse_rgb(&bytes[7..]) {
+            return (Some(fg), Some(bg), 13);
+        }
+    }
 
-fn parse_color<I>(bytes: &mut I) -> (Option<u8>, Option<u8>) where I: Iterator<Item=u8> {
-    (None, None)
+    return (Some(fg), None, 6);
 }
 
 impl Text {
@@ -35,75 +258,59 @@ impl Text {
         let mut ctcp = false;
         let mut action = false;
 
-        let mut bytes = if bytes[0] == 0x01 {
+        /*
+         * A CTCP message is delimited by \x01, although the closing one is often missing.
+         * Other than ACTIONs, we keep the whole body (e.g., "PING 12345") for the CTCP
+         * dispatcher.
+         */
+        let start = if bytes.first() == Some(&0x01) {
             ctcp = true;
             bytes.remove(0);
-            let n = bytes.len();
-            bytes.remove(n - 1);
-            if &bytes[..6] == b"ACTION" {
+            if bytes.last() == Some(&0x01) {
+                bytes.pop();
+            }
+            if bytes.starts_with(b"ACTION") && (bytes.len() == 6 || bytes[6] == b' ') {
                 action = true;
-                bytes.into_iter().skip(7)
+                if bytes.len() == 6 { 6 } else { 7 }
             } else {
-                bytes.into_iter().skip(0) // TODO: handle CTCP messages other than ACTIONs
+                0
             }
         } else {
-            bytes.into_iter().skip(0)
+            0
         };
 
-        let mut bold = false;
-        let mut italic = false;
-        let mut underline = false;
-        let mut reverse = false;
-
-        let mut fg = DEFAULT_COLOR;
-        let mut bg = DEFAULT_COLOR;
+        let mut format = TextChunk::plain(String::new());
 
         let mut chunk = Vec::new();
+        let mut i = start;
         loop {
-            let b = bytes.next();
-            let mut update = true;
-            match b {
-                Some(0x02) => { bold = !bold },
-                Some(0x1D) => { italic = !italic },
-                Some(0x1F) => { underline = !underline },
-                Some(0x16) => { reverse = !reverse },
-                Some(0x0F) => { 
-                    bold = false;
-                    italic = false;
-                    underline = false;
-                    reverse = false;
-                    fg = DEFAULT_COLOR;
-                    bg = DEFAULT_COLOR;
-                },
-                Some(0x03) => {
-                    match parse_color(&mut bytes) {
-                        (None, None)       => { fg = DEFAULT_COLOR; bg = DEFAULT_COLOR }
-                        (Some(f), None)    => { fg = f },
-                        (None, Some(b))    => { bg = b },
-                        (Some(f), Some(b)) => { fg = f; bg = b }
-                    }
-                },
-                Some(b)    => { chunk.push(b); update = false; }
-                _          => { }
+            let b = bytes.get(i).map(|&b| b);
+            i += 1;
+
+            let formatting = match b {
+                Some(b) => is_format_code(b),
+                None    => true
+            };
+
+            if !formatting {
+                chunk.push(b.unwrap());
+                continue;
             }
 
-            if update {
-                let s: String = String::from_utf8(chunk).expect("from utf8");
+            /*
+             * The text so far keeps the formatting it was written with.
+             */
+            if !chunk.is_empty() {
+                let s = String::from_utf8_lossy(&chunk).into_owned();
                 raw.push_str(&s);
-                chunks.push(TextChunk {
-                    text: s,
-                    fg: fg,
-                    bg: bg,
-                    bold: bold,
-                    italic: italic,
-                    underline: underline,
-                    reverse: reverse
-                });
-
+                chunks.push(TextChunk { text: s, .. format.clone() });
                 chunk = Vec::new();
             }
 
-            if b.is_none() { break }
+            match b {
+                Some(c) => { i += format.apply(c, &bytes[i..]) },
+                None    => break
+            }
         }
 
         return Text {
@@ -119,31 +326,7 @@ impl Text {
             raw: s.clone(),
             ctcp: false,
             action: false,
-            chunks: vec![TextChunk {
-                text: s,
-                fg: DEFAULT_COLOR,
-                bg: DEFAULT_COLOR,
-                bold: false,
-                italic: false,
-                underline: false,
-                reverse: false
-            }]
-        };
-    }
-    pub fn action(s: String) -> Text {
-        return Text {
-            raw: s.clone(),
-            ctcp: true,
-            action: true,
-            chunks: vec![TextChunk {
-                text: s,
-                fg: DEFAULT_COLOR,
-                bg: DEFAULT_COLOR,
-                bold: false,
-                italic: false,
-                underline: false,
-                reverse: false
-            }]
+            chunks: vec![TextChunk::plain(s)]
         };
     }
 
@@ -155,7 +338,183 @@ impl Text {
         return &self.chunks[..];
     }
 
+    /*
+     * The formatting of the text, for the client to highlight: a space-separated list
+     * of start:end:attributes, where start and end are byte offsets into the line that
+     * the text begins `offset` bytes into, and the attributes are a comma-separated
+     * list of fg=RRGGBB, bg=RRGGBB, b (bold), i (italic), u (underline), r (reverse),
+     * m (monospace) and s (strikethrough). Unformatted text is left out.
+     */
+    pub fn spans(&self, offset: usize) -> String {
+        let mut spans = Vec::new();
+        let mut start = offset;
+        for chunk in &self.chunks {
+            let end = start + chunk.text.len();
+            let attributes = chunk.attributes();
+            if !attributes.is_empty() && end > start {
+                spans.push(format!("{}:{}:{}", start, end, attributes.join(",")));
+            }
+            start = end;
+        }
+        return spans.join(" ");
+    }
+
     pub fn decorate_nick(nick: &str) -> Text {
         return Text::from_string(format!("<{}>", nick));
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_ctcp() {
+        let text = Text::from_bytes(b"\x01ACTION waves\x01".to_vec());
+        assert!(text.ctcp && text.action);
+        assert_eq!(text.text(), "waves");
+
+        let text = Text::from_bytes(b"\x01PING 12345".to_vec());
+        assert!(text.ctcp && !text.action);
+        assert_eq!(text.text(), "PING 12345");
+
+        for short in &[&b"\x01"[..], b"\x01\x01", b"\x01ACT\x01", b"\x01ACTION\x01", b""] {
+            Text::from_bytes(short.to_vec());
+        }
+    }
+
+    /*
+     * The text of each chunk with its colors.
+     */
+    fn colors(bytes: &[u8]) -> Vec<(String, Color, Color)> {
+        return Text::from_bytes(bytes.to_vec()).chunks().iter().map(|c| (c.text.clone(), c.fg, c.bg)).collect();
+    }
+
+    fn text(s: &str, fg: Color, bg: Color) -> (String, Color, Color) {
+        return (s.to_string(), fg, bg);
+    }
+
+    #[test]
+    fn test_plain() {
+        let t = Text::from_bytes(b"hello, world".to_vec());
+        assert_eq!(t.text(), "hello, world");
+        assert_eq!(t.chunks().len(), 1);
+        assert_eq!(Text::from_bytes(Vec::new()).chunks().len(), 0);
+    }
+
+    #[test]
+    fn test_toggles() {
+        let t = Text::from_bytes(b"a\x02b\x1Dc\x1Fd\x16e\x11f\x1Eg\x02h\x0Fi".to_vec());
+        assert_eq!(t.text(), "abcdefghi");
+
+        let flags = t.chunks().iter().map(|c| {
+            (c.bold, c.italic, c.underline, c.reverse, c.monospace, c.strikethrough)
+        }).collect::<Vec<_>>();
+        assert_eq!(flags, vec![
+            (false, false, false, false, false, false),
+            (true,  false, false, false, false, false),
+            (true,  true,  false, false, false, false),
+            (true,  true,  true,  false, false, false),
+            (true,  true,  true,  true,  false, false),
+            (true,  true,  true,  true,  true,  false),
+            (true,  true,  true,  true,  true,  true),
+            (false, true,  true,  true,  true,  true),
+            (false, false, false, false, false, false)
+        ]);
+    }
+
+    #[test]
+    fn test_color_digits() {
+        use super::Color::*;
+        assert_eq!(colors(b"\x034red"), vec![text("red", Palette(4), Default)]);
+        assert_eq!(colors(b"\x0304red"), vec![text("red", Palette(4), Default)]);
+        assert_eq!(colors(b"\x030412"), vec![text("12", Palette(4), Default)]);
+        assert_eq!(colors(b"\x034,12x"), vec![text("x", Palette(4), Palette(12))]);
+        assert_eq!(colors(b"\x0304,012x"), vec![text("2x", Palette(4), Palette(1))]);
+        assert_eq!(colors(b"\x039,"), vec![text(",", Palette(9), Default)]);
+        assert_eq!(colors(b"\x034,x"), vec![text(",x", Palette(4), Default)]);
+        assert_eq!(colors(b"\x034, 5"), vec![text(", 5", Palette(4), Default)]);
+        assert_eq!(colors(b"\x0399,99x"), vec![text("x", Default, Default)]);
+    }
+
+    #[test]
+    fn test_color_changes() {
+        use super::Color::*;
+
+        /*
+         * Only the foreground changes when no background is given.
+         */
+        assert_eq!(colors(b"\x034,2a\x035b"), vec![
+            text("a", Palette(4), Palette(2)),
+            text("b", Palette(5), Palette(2))
+        ]);
+
+        /*
+         * \x03 on its own resets both, and so does \x03 followed by a comma.
+         */
+        assert_eq!(colors(b"\x034,2a\x03b"), vec![
+            text("a", Palette(4), Palette(2)),
+            text("b", Default, Default)
+        ]);
+        assert_eq!(colors(b"\x034,2a\x03,5b"), vec![
+            text("a", Palette(4), Palette(2)),
+            text(",5b", Default, Default)
+        ]);
+        assert_eq!(colors(b"\x034a\x0Fb"), vec![
+            text("a", Palette(4), Default),
+            text("b", Default, Default)
+        ]);
+        assert_eq!(colors(b"a\x03"), vec![text("a", Default, Default)]);
+        assert_eq!(colors(b"\x03\x034a"), vec![text("a", Palette(4), Default)]);
+    }
+
+    #[test]
+    fn test_extended_palette() {
+        use super::Color::*;
+        assert_eq!(colors(b"\x0316,98x"), vec![text("x", Palette(16), Palette(98))]);
+        assert_eq!(colors(b"\x03100"), vec![text("0", Palette(10), Default)]);
+        assert_eq!(Palette(0).rgb(), Some((255, 255, 255)));
+        assert_eq!(Palette(4).rgb(), Some((255, 0, 0)));
+        assert_eq!(Palette(16).rgb(), Some((0x47, 0, 0)));
+        assert_eq!(Palette(52).rgb(), Some((255, 0, 0)));
+        assert_eq!(Palette(98).rgb(), Some((255, 255, 255)));
+        assert_eq!(Default.rgb(), None);
+    }
+
+    #[test]
+    fn test_hex_colors() {
+        use super::Color::*;
+        assert_eq!(colors(b"\x04FF8800orange"), vec![text("orange", Rgb(255, 136, 0), Default)]);
+        assert_eq!(colors(b"\x04ff8800,000080x"), vec![text("x", Rgb(255, 136, 0), Rgb(0, 0, 128))]);
+        assert_eq!(colors(b"\x04FF8800,0080x"), vec![text(",0080x", Rgb(255, 136, 0), Default)]);
+        assert_eq!(colors(b"\x04FF88x"), vec![text("FF88x", Default, Default)]);
+        assert_eq!(colors(b"\x04+FFFFFx"), vec![text("+FFFFFx", Default, Default)]);
+        assert_eq!(colors(b"\x04FF0000a\x04b"), vec![
+            text("a", Rgb(255, 0, 0), Default),
+            text("b", Default, Default)
+        ]);
+        assert_eq!(colors(b"\x034,5a\x04FFFFFFb"), vec![
+            text("a", Palette(4), Palette(5)),
+            text("b", Rgb(255, 255, 255), Palette(5))
+        ]);
+        assert_eq!(Rgb(1, 2, 3).rgb(), Some((1, 2, 3)));
+    }
+
+    #[test]
+    fn test_spans() {
+        assert_eq!(Text::from_string("plain".to_string()).spans(10), "");
+        assert_eq!(Text::from_bytes(b"plain".to_vec()).spans(10), "");
+
+        let t = Text::from_bytes("a \x02bold\x02 \x034,1red\x0F \x1D\x1Fé\x04FF8800x".as_bytes().to_vec());
+        assert_eq!(t.text(), "a bold red éx");
+        assert_eq!(t.spans(0), "2:6:b 7:10:fg=ff0000,bg=000000 11:13:i,u 13:14:fg=ff8800,i,u");
+        assert_eq!(t.spans(5), "7:11:b 12:15:fg=ff0000,bg=000000 16:18:i,u 18:19:fg=ff8800,i,u");
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(Text::from_bytes("\x02héllo\x02 wörld".as_bytes().to_vec()).text(), "héllo wörld");
+        assert_eq!(Text::from_bytes(b"caf\xe9".to_vec()).text(), "caf\u{fffd}");
+    }
+}
@@ -26,18 +26,13 @@ impl User {
             host: None
         }
     }
-
-    pub fn actual_nick(&self) -> &str {
-        let i = self.nick.find(|c| c != '@' && c != '+').unwrap();
-        return &self.nick[i..];
-    }
 }
 
 impl ToString for User {
     fn to_string(&self) -> String {
         return format!(
             "{}!{}@{}",
-            self.actual_nick(),
+            self.nick,
             self.username.as_ref().map(|s| &s[..]).unwrap_or(""),
             self.host.as_ref().map(|s| &s[..]).unwrap_or("")
         );
@@ -46,12 +41,12 @@ impl ToString for User {
 
 impl PartialEq<User> for User {
     fn eq(&self, other: &User) -> bool {
-        return self.actual_nick() == other.actual_nick();
+        return self.nick == other.nick;
     }
 }
 
 impl Hash for User {
     fn hash<H>(&self, hasher: &mut H) where H: Hasher {
-        self.actual_nick().hash(hasher);
+        self.nick.hash(hasher);
     }
 }
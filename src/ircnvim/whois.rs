@@ -0,0 +1,230 @@
+use ircnvim::irc::IrcMessage;
+use time;
+use time::Timespec;
+
+/*
+ * Everything the server told us in reply to a WHOIS or WHOWAS, collected until
+ * RPL_ENDOFWHOIS (318) or RPL_ENDOFWHOWAS (369) arrives.
+ */
+pub struct WhoisInfo {
+    pub nick: String,
+    pub was: bool,
+    pub username: Option<String>,
+    pub host: Option<String>,
+    pub realname: Option<String>,
+    pub server: Option<String>,
+    pub server_info: Option<String>,
+    pub account: Option<String>,
+    pub channels: Vec<String>,
+    pub idle: Option<u64>,
+    pub signon: Option<i64>,
+    pub away: Option<String>,
+    pub operator: Option<String>,
+    pub secure: bool,
+    pub error: Option<String>,
+    pub other: Vec<String>
+}
+
+impl WhoisInfo {
+    pub fn new(nick: &str, was: bool) -> WhoisInfo {
+        return WhoisInfo {
+            nick: nick.to_string(),
+            was: was,
+            username: None,
+            host: None,
+            realname: None,
+            server: None,
+            server_info: None,
+            account: None,
+            channels: Vec::new(),
+            idle: None,
+            signon: None,
+            away: None,
+            operator: None,
+            secure: false,
+            error: None,
+            other: Vec::new()
+        };
+    }
+
+    /*
+     * Record one reply. They all look like
+     *
+     * :server <numeric> <our nick> <nick> ...
+     */
+    pub fn add(&mut self, numeric: u16, message: &IrcMessage) {
+        let params = message.params();
+        let param = |i: usize| params.get(i).map(|t| t.text().to_string());
+        let last = params.last().map(|t| t.text().to_string());
+
+        match numeric {
+            311 | 314 => {
+                self.username = param(2);
+                self.host = param(3);
+                self.realname = if params.len() > 5 { last } else { None };
+            },
+            312       => {
+                self.server = param(2);
+                self.server_info = if params.len() > 3 { last } else { None };
+            },
+            313       => self.operator = last,
+            317       => {
+                self.idle = param(2).and_then(|s| s.parse().ok());
+                if params.len() > 4 {
+                    self.signon = param(3).and_then(|s| s.parse().ok());
+                }
+            },
+            319       => {
+                if let Some(channels) = last {
+                    self.channels.extend(channels.split_whitespace().map(|c| c.to_string()));
+                }
+            },
+            301       => self.away = last,
+            330       => self.account = param(2),
+            671       => self.secure = true,
+            401 | 406 => self.error = last,
+            _         => {
+                if params.len() > 2 {
+                    self.other.push(message.sequence(2));
+                }
+            }
+        }
+    }
+
+    /*
+     * The lines to show in the room the command came from.
+     */
+    pub fn render(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let command = if self.was { "WHOWAS" } else { "WHOIS" };
+
+        if let Some(ref error) = self.error {
+            lines.push(format!("{}: {}", self.nick, error));
+            return lines;
+        }
+
+        let mask = match (&self.username, &self.host) {
+            (&Some(ref u), &Some(ref h)) => format!(" [{}@{}]", u, h),
+            _                            => String::new()
+        };
+        let realname = self.realname.as_ref().map(|r| format!(": {}", r)).unwrap_or(String::new());
+        lines.push(format!("{} {}{}{}", self.nick, if self.was { "was" } else { "is" }, mask, realname));
+
+        if let Some(ref server) = self.server {
+            match self.server_info {
+                Some(ref info) => lines.push(format!("    server:   {} ({})", server, info)),
+                None           => lines.push(format!("    server:   {}", server))
+            }
+        }
+        if let Some(ref account) = self.account {
+            lines.push(format!("    account:  {}", account));
+        }
+        if !self.channels.is_empty() {
+            lines.push(format!("    channels: {}", self.channels.join(" ")));
+        }
+        if let Some(idle) = self.idle {
+            match self.signon {
+                Some(signon) => {
+                    let signon = time::at(Timespec::new(signon, 0)).rfc822().to_string();
+                    lines.push(format!("    idle:     {}, signed on {}", format_duration(idle), signon));
+                },
+                None         => lines.push(format!("    idle:     {}", format_duration(idle)))
+            }
+        }
+        if let Some(ref away) = self.away {
+            lines.push(format!("    away:     {}", away));
+        }
+        if let Some(ref operator) = self.operator {
+            lines.push(format!("    operator: {}", operator));
+        }
+        if self.secure {
+            lines.push(format!("    secure:   is using a secure connection"));
+        }
+        for other in &self.other {
+            lines.push(format!("    {}", other));
+        }
+
+        lines.push(format!("End of {} for {}", command, self.nick));
+        return lines;
+    }
+}
+
+/*
+ * e.g., 3725 -> "1h 2m 5s"
+ */
+fn format_duration(seconds: u64) -> String {
+    let (d, h, m, s) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60, seconds % 60);
+    return if d > 0 {
+        format!("{}d {}h {}m {}s", d, h, m, s)
+    } else if h > 0 {
+        format!("{}h {}m {}s", h, m, s)
+    } else if m > 0 {
+        format!("{}m {}s", m, s)
+    } else {
+        format!("{}s", s)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use ircnvim::irc::IrcMessage;
+
+    fn collect(info: &mut WhoisInfo, replies: &[(u16, &str)]) {
+        for &(numeric, line) in replies {
+            info.add(numeric, &IrcMessage::parse(line.as_bytes()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_whois() {
+        let mut info = WhoisInfo::new("foo", false);
+        collect(&mut info, &[
+            (311, ":irc.example.com 311 me foo ~foo example.com * :Foo Bar"),
+            (319, ":irc.example.com 319 me foo :@#rust +#neovim"),
+            (312, ":irc.example.com 312 me foo irc.example.com :Example Server"),
+            (301, ":irc.example.com 301 me foo :gone fishing"),
+            (671, ":irc.example.com 671 me foo :is using a secure connection"),
+            (317, ":irc.example.com 317 me foo 3725 1451606400 :seconds idle, signon time"),
+            (330, ":irc.example.com 330 me foo foo_account :is logged in as"),
+            (378, ":irc.example.com 378 me foo :is connecting from *@10.0.0.1 10.0.0.1")
+        ]);
+
+        let lines = info.render();
+        assert_eq!(lines[0], "foo is [~foo@example.com]: Foo Bar");
+        assert_eq!(lines[1], "    server:   irc.example.com (Example Server)");
+        assert_eq!(lines[2], "    account:  foo_account");
+        assert_eq!(lines[3], "    channels: @#rust +#neovim");
+        assert!(lines[4].starts_with("    idle:     1h 2m 5s, signed on "));
+        assert_eq!(lines[5], "    away:     gone fishing");
+        assert_eq!(lines[6], "    secure:   is using a secure connection");
+        assert_eq!(lines[7], "    is connecting from *@10.0.0.1 10.0.0.1");
+        assert_eq!(lines[8], "End of WHOIS for foo");
+    }
+
+    #[test]
+    fn test_whowas() {
+        let mut info = WhoisInfo::new("foo", true);
+        collect(&mut info, &[
+            (314, ":irc.example.com 314 me foo ~foo example.com * :Foo Bar"),
+            (312, ":irc.example.com 312 me foo irc.example.com :Sat Jan  2 10:00:00 2016")
+        ]);
+        assert_eq!(info.render(), vec![
+            "foo was [~foo@example.com]: Foo Bar",
+            "    server:   irc.example.com (Sat Jan  2 10:00:00 2016)",
+            "End of WHOWAS for foo"
+        ]);
+
+        let mut info = WhoisInfo::new("bar", true);
+        collect(&mut info, &[(406, ":irc.example.com 406 me bar :There was no such nickname")]);
+        assert_eq!(info.render(), vec!["bar: There was no such nickname"]);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(5), "5s");
+        assert_eq!(format_duration(65), "1m 5s");
+        assert_eq!(format_duration(90061), "1d 1h 1m 1s");
+    }
+}
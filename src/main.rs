@@ -1,6 +1,7 @@
 #![feature(slice_patterns)]
 #![feature(const_fn)]
 
+extern crate openssl;
 extern crate regex;
 extern crate time;
 extern crate rustc_serialize;
@@ -22,6 +23,21 @@ mod ircnvim {
     pub mod room;
     pub mod irc;
     pub mod user;
+    pub mod cap;
+    pub mod conn;
+    pub mod nickserv;
+    pub mod sasl;
+    pub mod nick;
+    pub mod isupport;
+    pub mod mode;
+    pub mod whois;
+    pub mod errors;
+    pub mod ctcp;
+    pub mod dcc;
+    pub mod markup;
+    pub mod split;
+    pub mod lines;
+    pub mod queue;
 
     use regex::Regex;
     use std::ptr;